@@ -0,0 +1,218 @@
+use std::io::{IsTerminal, Read};
+use std::rc::Rc;
+
+use lisp_interpreter::env::Environment;
+use lisp_interpreter::errors::EvalError;
+use lisp_interpreter::eval::{eval_with_options, EvalOptions};
+use lisp_interpreter::value::Value;
+
+use crate::repl;
+
+const USAGE: &str = "Usage:
+  lisp_interpreter                      Start the interactive REPL
+  lisp_interpreter run <file> [args]    Run a script, '-' reads it from stdin
+  lisp_interpreter -e <expr> [args]     Evaluate an expression and print the result
+  lisp_interpreter --help               Show this message
+
+Options, given before the command:
+  --trace             Print tokens, the AST and every evaluation step to stderr
+  --no-color          Never color error messages, also implied by NO_COLOR or a non-terminal stderr
+  --collapse-floats   Turn whole float results like (+ 2.5 2.5) into integers, as earlier versions did
+
+Script arguments are available as the list `command-line-args`.";
+
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    Repl,
+    Run { path: String, args: Vec<String> },
+    Eval { expr: String, args: Vec<String> },
+    Help,
+}
+
+pub fn parse_args(args: &[String]) -> Result<Command, String> {
+    let Some(first) = args.first() else {
+        return Ok(Command::Repl);
+    };
+
+    match first.as_str() {
+        "run" => match args.get(1) {
+            Some(path) => Ok(Command::Run {
+                path: path.clone(),
+                args: args[2..].to_vec(),
+            }),
+            None => Err("'run' expects a file name".to_string()),
+        },
+        "-e" => match args.get(1) {
+            Some(expr) => Ok(Command::Eval {
+                expr: expr.clone(),
+                args: args[2..].to_vec(),
+            }),
+            None => Err("'-e' expects an expression".to_string()),
+        },
+        "repl" => Ok(Command::Repl),
+        "-h" | "--help" => Ok(Command::Help),
+        other => Err(format!("Unknown command '{}'", other)),
+    }
+}
+
+fn read_source(path: &str) -> Result<String, String> {
+    if path == "-" {
+        let mut source = String::new();
+        return match std::io::stdin().read_to_string(&mut source) {
+            Ok(_) => Ok(source),
+            Err(e) => Err(format!("Failed to read from stdin: {}", e)),
+        };
+    }
+    std::fs::read_to_string(path).map_err(|e| format!("Failed to read '{}': {}", path, e))
+}
+
+fn script_env(args: &[String]) -> Environment {
+    let env = Environment::global();
+    let args = args.iter().map(|arg| Value::String(arg.as_str().into()));
+    env.define_var("command-line-args", Value::list(args));
+    env
+}
+
+fn use_color() -> bool {
+    std::io::stderr().is_terminal() && std::env::var_os("NO_COLOR").is_none()
+}
+
+pub fn print_error(err: &EvalError, source: &str, source_name: Option<Rc<str>>, color: bool) {
+    eprint!("{}", err.diagnostic(source_name).render(source, color));
+}
+
+fn run_source(
+    source: &str,
+    args: &[String],
+    print_result: bool,
+    options: &EvalOptions,
+    color: bool,
+) -> i32 {
+    let mut env = script_env(args);
+    match eval_with_options(source, &mut env, options) {
+        Ok(result) => {
+            if print_result {
+                println!("{}", result);
+            }
+            0
+        }
+        Err(err) => {
+            print_error(&err, source, options.source_name.clone(), color);
+            1
+        }
+    }
+}
+
+/// Runs the command line front end and returns the process exit code.
+pub fn main(args: &[String]) -> i32 {
+    let mut options = EvalOptions::default();
+    let mut color = use_color();
+    let mut args = args;
+    while let Some((flag, rest)) = args.split_first() {
+        match flag.as_str() {
+            "--trace" => options.set_trace(true),
+            "--no-color" => color = false,
+            "--collapse-floats" => options.collapse_floats = true,
+            _ => break,
+        }
+        args = rest;
+    }
+    let command = match parse_args(args) {
+        Ok(command) => command,
+        Err(msg) => {
+            eprintln!("Error: {}\n\n{}", msg, USAGE);
+            return 2;
+        }
+    };
+
+    match command {
+        Command::Repl => {
+            repl::run_repl(options, color);
+            0
+        }
+        Command::Help => {
+            println!("{}", USAGE);
+            0
+        }
+        Command::Run { path, args } => match read_source(&path) {
+            Ok(source) => {
+                let name = if path == "-" {
+                    "<stdin>"
+                } else {
+                    path.as_str()
+                };
+                options.source_name = Some(Rc::from(name));
+                run_source(&source, &args, false, &options, color)
+            }
+            Err(msg) => {
+                eprintln!("Error: {}", msg);
+                1
+            }
+        },
+        Command::Eval { expr, args } => run_source(&expr, &args, true, &options, color),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse_args() {
+        assert_eq!(parse_args(&[]), Ok(Command::Repl));
+        assert_eq!(parse_args(&args(&["--help"])), Ok(Command::Help));
+        assert_eq!(
+            parse_args(&args(&["run", "main.lisp", "a", "-e"])),
+            Ok(Command::Run {
+                path: "main.lisp".to_string(),
+                args: args(&["a", "-e"]),
+            })
+        );
+        assert_eq!(
+            parse_args(&args(&["run", "-"])),
+            Ok(Command::Run {
+                path: "-".to_string(),
+                args: vec![],
+            })
+        );
+        assert_eq!(
+            parse_args(&args(&["-e", "(+ 1 2)", "x"])),
+            Ok(Command::Eval {
+                expr: "(+ 1 2)".to_string(),
+                args: args(&["x"]),
+            })
+        );
+
+        assert!(parse_args(&args(&["run"])).is_err());
+        assert!(parse_args(&args(&["-e"])).is_err());
+        assert!(parse_args(&args(&["--unknown"])).is_err());
+    }
+
+    #[test]
+    fn test_run_source() {
+        let options = EvalOptions::default();
+        assert_eq!(run_source("(+ 1 2)", &[], false, &options, false), 0);
+        assert_eq!(
+            run_source(
+                "(car command-line-args)",
+                &args(&["a"]),
+                false,
+                &options,
+                false
+            ),
+            0
+        );
+        assert_eq!(
+            run_source("(+ 1 undefined)", &[], false, &options, false),
+            1
+        );
+        assert_eq!(
+            run_source("(+ 1 \"unclosed)", &[], false, &options, false),
+            1
+        );
+    }
+}
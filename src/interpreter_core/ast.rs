@@ -1,4 +1,5 @@
 use crate::operatortype::Operator;
+use crate::span::Span;
 use crate::value::Value;
 use std::fmt;
 
@@ -7,14 +8,17 @@ pub enum AstNodeValue {
     Operator(Operator),
     Literal(Value),
     Identifier(String),
+    Expression,
     Quote,
+    /// The datum after the dot of a list like '(1 . 2), always the last child of the list
+    DottedTail,
     Root,
-    Values,
 }
 
 #[derive(Debug, Clone)]
 pub struct AstNode {
     pub value: AstNodeValue,
+    pub span: Option<Span>,
     children: Vec<AstNode>,
 }
 
@@ -22,6 +26,15 @@ impl AstNode {
     pub fn new(value: AstNodeValue) -> AstNode {
         AstNode {
             value,
+            span: None,
+            children: Vec::new(),
+        }
+    }
+
+    pub fn with_span(value: AstNodeValue, span: Span) -> AstNode {
+        AstNode {
+            value,
+            span: Some(span),
             children: Vec::new(),
         }
     }
@@ -31,15 +44,8 @@ impl AstNode {
     }
 
     pub fn children(&self) -> &Vec<AstNode> {
-        if let AstNodeValue::Values = self.value {
-            return &self.children.get(0).unwrap().children();
-        }
         &self.children
     }
-
-    pub fn append_children(&mut self, nodes: Vec<AstNode>) {
-        self.children.extend(nodes);
-    }
 }
 
 impl fmt::Display for AstNode {
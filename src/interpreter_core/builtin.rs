@@ -1,15 +1,541 @@
-use crate::errors::RuntimeError;
-use crate::value::Value;
+use crate::env::Environment;
+use crate::errors::{RuntimeError, RuntimeErrorKind};
+use crate::eval;
+use crate::operatortype::Operator;
+use crate::symbol::Symbol;
+use crate::value::{Pair, Value};
+use num_bigint::BigInt;
+use num_integer::Integer;
+use num_rational::BigRational;
+use num_traits::One;
+use std::cmp::Ordering;
+use std::rc::Rc;
 
-#[allow(dead_code)]
+pub type BuiltinFn = fn(&[Value]) -> Result<Value, RuntimeError>;
+
+#[derive(Debug)]
 pub struct BuiltinFunction {
     pub name: &'static str,
-    pub func: fn(&[Value]) -> Result<Value, RuntimeError>,
+    pub func: BuiltinFn,
     pub min_args: usize,
     pub max_args: Option<usize>,
 }
 
-#[allow(dead_code)]
-trait BuiltinFunctionT {
-    fn eval(args: &[Value]) -> Result<Value, RuntimeError>;
+impl BuiltinFunction {
+    pub const fn new(
+        name: &'static str,
+        func: BuiltinFn,
+        min_args: usize,
+        max_args: Option<usize>,
+    ) -> Self {
+        BuiltinFunction {
+            name,
+            func,
+            min_args,
+            max_args,
+        }
+    }
+
+    pub fn call(&self, args: &[Value]) -> Result<Value, RuntimeError> {
+        if args.len() < self.min_args || self.max_args.is_some_and(|max| args.len() > max) {
+            let expected = match self.max_args {
+                Some(max) if max == self.min_args => format!("{}", max),
+                Some(max) => format!("{} to {}", self.min_args, max),
+                None => format!("at least {}", self.min_args),
+            };
+            return Err(RuntimeError::arity(format!(
+                "{} expects {} argument(s), got {}",
+                self.name,
+                expected,
+                args.len()
+            )));
+        }
+        (self.func)(args)
+    }
+}
+
+pub static BUILTINS: &[BuiltinFunction] = &[
+    BuiltinFunction::new("+", plus, 0, None),
+    BuiltinFunction::new("-", minus, 1, None),
+    BuiltinFunction::new("*", mul, 0, None),
+    BuiltinFunction::new("/", div, 1, None),
+    BuiltinFunction::new("//", int_div, 1, None),
+    BuiltinFunction::new("%", modulo, 1, None),
+    BuiltinFunction::new("^", power, 1, None),
+    BuiltinFunction::new("=", eq, 1, None),
+    BuiltinFunction::new("!=", neq, 0, None),
+    BuiltinFunction::new("<", lt, 0, None),
+    BuiltinFunction::new("<=", leq, 0, None),
+    BuiltinFunction::new(">", gt, 0, None),
+    BuiltinFunction::new(">=", geq, 0, None),
+    BuiltinFunction::new("abs", abs, 1, Some(1)),
+    BuiltinFunction::new("min", min, 1, None),
+    BuiltinFunction::new("max", max, 1, None),
+    BuiltinFunction::new("sqrt", sqrt, 1, Some(1)),
+    BuiltinFunction::new("exact->inexact", exact_to_inexact, 1, Some(1)),
+    BuiltinFunction::new("inexact", exact_to_inexact, 1, Some(1)),
+    BuiltinFunction::new("inexact->exact", exact, 1, Some(1)),
+    BuiltinFunction::new("exact", exact, 1, Some(1)),
+    BuiltinFunction::new("round", round, 1, Some(1)),
+    BuiltinFunction::new("floor", floor, 1, Some(1)),
+    BuiltinFunction::new("ceiling", ceiling, 1, Some(1)),
+    BuiltinFunction::new("truncate", truncate, 1, Some(1)),
+    BuiltinFunction::new("numerator", numerator, 1, Some(1)),
+    BuiltinFunction::new("denominator", denominator, 1, Some(1)),
+    BuiltinFunction::new("display", display, 1, Some(1)),
+    BuiltinFunction::new("newline", newline, 0, Some(0)),
+    BuiltinFunction::new("cons", cons, 2, Some(2)),
+    BuiltinFunction::new("car", car, 1, Some(1)),
+    BuiltinFunction::new("cdr", cdr, 1, Some(1)),
+    BuiltinFunction::new("list", list, 0, None),
+    BuiltinFunction::new("null?", is_null, 1, Some(1)),
+    BuiltinFunction::new("pair?", is_pair, 1, Some(1)),
+    BuiltinFunction::new("length", length, 1, Some(1)),
+    BuiltinFunction::new("append", append, 0, None),
+    BuiltinFunction::new("reverse", reverse, 1, Some(1)),
+    BuiltinFunction::new("apply", apply, 2, None),
+    BuiltinFunction::new("map", map, 2, None),
+    BuiltinFunction::new("eq?", is_eq, 2, Some(2)),
+    BuiltinFunction::new("symbol?", is_symbol, 1, Some(1)),
+    BuiltinFunction::new("symbol->string", symbol_to_string, 1, Some(1)),
+    BuiltinFunction::new("string->symbol", string_to_symbol, 1, Some(1)),
+    BuiltinFunction::new("error", error, 1, None),
+    BuiltinFunction::new("raise", raise, 1, Some(1)),
+    BuiltinFunction::new("error?", is_error, 1, Some(1)),
+    BuiltinFunction::new("error-message", error_message, 1, Some(1)),
+    BuiltinFunction::new("error-kind", error_kind, 1, Some(1)),
+    BuiltinFunction::new("error-irritants", error_irritants, 1, Some(1)),
+];
+
+pub fn register_builtins(env: &Environment) {
+    for builtin in BUILTINS {
+        env.define_var(builtin.name, Value::Builtin(builtin));
+    }
+}
+
+/// Whether `builtin` is the entry of `BUILTINS` that `name` is bound to globally, i.e. the name
+/// has not been rebound to something else
+pub fn is_global(builtin: &BuiltinFunction, name: &str) -> bool {
+    BUILTINS
+        .iter()
+        .any(|global| global.name == name && std::ptr::eq(global, builtin))
+}
+
+// Operators called as values, e.g. (define add +), direct calls like (+ 1 2) skip these
+fn apply_operator(op: Operator, args: &[Value]) -> Result<Value, RuntimeError> {
+    op.apply(args)
+}
+
+fn plus(args: &[Value]) -> Result<Value, RuntimeError> {
+    apply_operator(Operator::Plus, args)
+}
+
+fn minus(args: &[Value]) -> Result<Value, RuntimeError> {
+    apply_operator(Operator::Minus, args)
+}
+
+fn mul(args: &[Value]) -> Result<Value, RuntimeError> {
+    apply_operator(Operator::Mul, args)
+}
+
+fn div(args: &[Value]) -> Result<Value, RuntimeError> {
+    apply_operator(Operator::Div, args)
+}
+
+fn int_div(args: &[Value]) -> Result<Value, RuntimeError> {
+    apply_operator(Operator::IntDiv, args)
+}
+
+fn modulo(args: &[Value]) -> Result<Value, RuntimeError> {
+    apply_operator(Operator::Modulo, args)
+}
+
+fn power(args: &[Value]) -> Result<Value, RuntimeError> {
+    apply_operator(Operator::Power, args)
+}
+
+fn eq(args: &[Value]) -> Result<Value, RuntimeError> {
+    apply_operator(Operator::Eq, args)
+}
+
+fn neq(args: &[Value]) -> Result<Value, RuntimeError> {
+    apply_operator(Operator::Neq, args)
+}
+
+fn lt(args: &[Value]) -> Result<Value, RuntimeError> {
+    apply_operator(Operator::Lt, args)
+}
+
+fn leq(args: &[Value]) -> Result<Value, RuntimeError> {
+    apply_operator(Operator::Leq, args)
+}
+
+fn gt(args: &[Value]) -> Result<Value, RuntimeError> {
+    apply_operator(Operator::Gt, args)
+}
+
+fn geq(args: &[Value]) -> Result<Value, RuntimeError> {
+    apply_operator(Operator::Geq, args)
+}
+
+fn expect_number(name: &str, value: &Value) -> Result<(), RuntimeError> {
+    if !value.is_numeric() {
+        return Err(RuntimeError::type_mismatch(format!(
+            "{} expects a number, found {}",
+            name, value
+        )));
+    }
+    Ok(())
+}
+
+/// The elements of a proper list
+fn expect_list(name: &str, value: &Value) -> Result<Vec<Value>, RuntimeError> {
+    if !value.is_list() {
+        return Err(RuntimeError::type_mismatch(format!(
+            "{} expects a list, found {}",
+            name, value
+        )));
+    }
+    Ok(value.iter().cloned().collect())
+}
+
+fn expect_pair<'a>(name: &str, value: &'a Value) -> Result<&'a Pair, RuntimeError> {
+    match value {
+        Value::Pair(pair) => Ok(pair),
+        Value::Nil => Err(RuntimeError::new(
+            RuntimeErrorKind::InvalidArgument,
+            format!("{} cannot be applied to an empty list", name),
+        )),
+        other => Err(RuntimeError::type_mismatch(format!(
+            "{} expects a list, found {}",
+            name, other
+        ))),
+    }
+}
+
+fn expect_error<'a>(name: &str, value: &'a Value) -> Result<&'a RuntimeError, RuntimeError> {
+    match value {
+        Value::Error(err) => Ok(err),
+        other => Err(RuntimeError::type_mismatch(format!(
+            "{} expects an error, found {}",
+            name, other
+        ))),
+    }
+}
+
+fn abs(args: &[Value]) -> Result<Value, RuntimeError> {
+    match &args[0] {
+        Value::Float(v) => Ok(Value::Float(v.abs())),
+        value => {
+            expect_number("abs", value)?;
+            if value.compare_to(&Value::Int(0))? == Some(Ordering::Less) {
+                value.clone().checked_negate()
+            } else {
+                Ok(value.clone())
+            }
+        }
+    }
+}
+
+fn extremum(
+    name: &str,
+    args: &[Value],
+    keep_left: fn(Ordering) -> bool,
+) -> Result<Value, RuntimeError> {
+    let mut result = &args[0];
+    expect_number(name, result)?;
+    let mut unordered = false;
+    for arg in &args[1..] {
+        expect_number(name, arg)?;
+        match result.compare_to(arg)? {
+            Some(ordering) if keep_left(ordering) => {}
+            Some(_) => result = arg,
+            None => unordered = true,
+        }
+    }
+    // NaN has no place in the order, so it is the result as soon as it is among the arguments
+    if unordered {
+        return Ok(Value::Float(f64::NAN));
+    }
+    Ok(result.clone())
+}
+
+fn min(args: &[Value]) -> Result<Value, RuntimeError> {
+    extremum("min", args, Ordering::is_le)
+}
+
+fn max(args: &[Value]) -> Result<Value, RuntimeError> {
+    extremum("max", args, Ordering::is_ge)
+}
+
+fn sqrt(args: &[Value]) -> Result<Value, RuntimeError> {
+    match args[0].get_numeric_value_as_float() {
+        Some(v) if v >= 0.0 => Ok(Value::Float(v.sqrt())),
+        Some(_) => Err(RuntimeError::new(
+            RuntimeErrorKind::InvalidArgument,
+            format!("sqrt expects a non-negative number, found {}", args[0]),
+        )),
+        None => Err(RuntimeError::type_mismatch(format!(
+            "sqrt expects a number, found {}",
+            args[0]
+        ))),
+    }
+}
+
+fn exact_to_inexact(args: &[Value]) -> Result<Value, RuntimeError> {
+    match args[0].get_numeric_value_as_float() {
+        Some(v) => Ok(Value::Float(v)),
+        None => Err(RuntimeError::type_mismatch(format!(
+            "exact->inexact expects a number, found {}",
+            args[0]
+        ))),
+    }
+}
+
+fn expect_exact(name: &str, value: &Value) -> Result<BigRational, RuntimeError> {
+    value.to_rational().ok_or_else(|| {
+        RuntimeError::type_mismatch(format!("{} expects an exact number, found {}", name, value))
+    })
+}
+
+fn exact(args: &[Value]) -> Result<Value, RuntimeError> {
+    match &args[0] {
+        Value::Float(v) => match BigRational::from_float(*v) {
+            Some(v) => Ok(Value::from_rational(v)),
+            None => Err(RuntimeError::new(
+                RuntimeErrorKind::InvalidArgument,
+                format!("exact cannot convert {} to an exact number", v),
+            )),
+        },
+        value => {
+            expect_exact("exact", value)?;
+            Ok(value.clone())
+        }
+    }
+}
+
+/// Rounding keeps the exactness of its argument, e.g. (round 2.5) is 2.0 but (round 5/2) is 2.
+fn rounding(
+    name: &str,
+    args: &[Value],
+    exact: fn(&BigRational) -> BigRational,
+    inexact: fn(f64) -> f64,
+) -> Result<Value, RuntimeError> {
+    match &args[0] {
+        Value::Float(v) => Ok(Value::Float(inexact(*v))),
+        value => Ok(Value::from_rational(exact(&expect_exact(name, value)?))),
+    }
+}
+
+/// Rounds to the nearest integer and ties to the even one, like f64::round_ties_even.
+fn round_ties_even(value: &BigRational) -> BigRational {
+    let floor = value.floor();
+    let half = BigRational::new(BigInt::one(), BigInt::from(2));
+    match (value - &floor).cmp(&half) {
+        Ordering::Less => floor,
+        Ordering::Equal if floor.to_integer().is_even() => floor,
+        _ => floor + BigRational::one(),
+    }
+}
+
+fn round(args: &[Value]) -> Result<Value, RuntimeError> {
+    rounding("round", args, round_ties_even, f64::round_ties_even)
+}
+
+fn floor(args: &[Value]) -> Result<Value, RuntimeError> {
+    rounding("floor", args, BigRational::floor, f64::floor)
+}
+
+fn ceiling(args: &[Value]) -> Result<Value, RuntimeError> {
+    rounding("ceiling", args, BigRational::ceil, f64::ceil)
+}
+
+fn truncate(args: &[Value]) -> Result<Value, RuntimeError> {
+    rounding("truncate", args, BigRational::trunc, f64::trunc)
+}
+
+fn numerator(args: &[Value]) -> Result<Value, RuntimeError> {
+    let value = expect_exact("numerator", &args[0])?;
+    Ok(Value::from_bigint(value.numer().clone()))
+}
+
+fn denominator(args: &[Value]) -> Result<Value, RuntimeError> {
+    let value = expect_exact("denominator", &args[0])?;
+    Ok(Value::from_bigint(value.denom().clone()))
+}
+
+fn display(args: &[Value]) -> Result<Value, RuntimeError> {
+    // Strings are written without their quotes, everything else as it is printed by the REPL
+    match &args[0] {
+        Value::String(v) => print!("{}", v),
+        other => print!("{}", other),
+    }
+    Ok(Value::None)
+}
+
+fn newline(_args: &[Value]) -> Result<Value, RuntimeError> {
+    println!();
+    Ok(Value::None)
+}
+
+// The tail is shared rather than copied, any value can be the cdr of a pair
+fn cons(args: &[Value]) -> Result<Value, RuntimeError> {
+    Ok(Value::cons(args[0].clone(), args[1].clone()))
+}
+
+fn car(args: &[Value]) -> Result<Value, RuntimeError> {
+    Ok(expect_pair("car", &args[0])?.car.clone())
+}
+
+fn cdr(args: &[Value]) -> Result<Value, RuntimeError> {
+    Ok(expect_pair("cdr", &args[0])?.cdr.clone())
+}
+
+fn list(args: &[Value]) -> Result<Value, RuntimeError> {
+    Ok(Value::list(args.iter().cloned()))
+}
+
+fn is_null(args: &[Value]) -> Result<Value, RuntimeError> {
+    Ok(Value::Boolean(matches!(args[0], Value::Nil)))
+}
+
+fn is_pair(args: &[Value]) -> Result<Value, RuntimeError> {
+    Ok(Value::Boolean(matches!(args[0], Value::Pair(_))))
+}
+
+fn length(args: &[Value]) -> Result<Value, RuntimeError> {
+    let items = expect_list("length", &args[0])?;
+    Ok(Value::Int(items.len() as i64))
+}
+
+// The last list is shared, the ones before it are copied
+fn append(args: &[Value]) -> Result<Value, RuntimeError> {
+    let Some((last, init)) = args.split_last() else {
+        return Ok(Value::Nil);
+    };
+    let mut items = Vec::new();
+    for arg in init {
+        items.extend(expect_list("append", arg)?);
+    }
+    expect_list("append", last)?;
+    Ok(items
+        .into_iter()
+        .rev()
+        .fold(last.clone(), |tail, item| Value::cons(item, tail)))
+}
+
+fn reverse(args: &[Value]) -> Result<Value, RuntimeError> {
+    let items = expect_list("reverse", &args[0])?;
+    Ok(items
+        .into_iter()
+        .fold(Value::Nil, |tail, item| Value::cons(item, tail)))
+}
+
+/// (apply f a b '(c d)) calls f with a, b, c and d
+fn apply(args: &[Value]) -> Result<Value, RuntimeError> {
+    let (last, init) = args[1..].split_last().unwrap();
+    let mut call_args = init.to_vec();
+    call_args.extend(expect_list("apply", last)?);
+    eval::apply(&args[0], call_args)
+}
+
+/// (map f xs ys) calls f with the first elements of xs and ys, then the second ones and so on,
+/// up to the end of the shortest list
+fn map(args: &[Value]) -> Result<Value, RuntimeError> {
+    let mut lists = Vec::new();
+    for arg in &args[1..] {
+        lists.push(expect_list("map", arg)?.into_iter());
+    }
+    let mut results = Vec::new();
+    loop {
+        let mut call_args = Vec::new();
+        for list in &mut lists {
+            match list.next() {
+                Some(item) => call_args.push(item),
+                None => return Ok(Value::list(results)),
+            }
+        }
+        results.push(eval::apply(&args[0], call_args)?);
+    }
+}
+
+fn is_eq(args: &[Value]) -> Result<Value, RuntimeError> {
+    let eq = match (&args[0], &args[1]) {
+        (Value::Symbol(l), Value::Symbol(r)) => l == r,
+        (Value::Pair(l), Value::Pair(r)) => Rc::ptr_eq(l, r),
+        (Value::String(l), Value::String(r)) => Rc::ptr_eq(l, r),
+        (l, r) => l == r,
+    };
+    Ok(Value::Boolean(eq))
+}
+
+fn is_symbol(args: &[Value]) -> Result<Value, RuntimeError> {
+    Ok(Value::Boolean(matches!(args[0], Value::Symbol(_))))
+}
+
+fn symbol_to_string(args: &[Value]) -> Result<Value, RuntimeError> {
+    match &args[0] {
+        Value::Symbol(symbol) => Ok(Value::String(symbol.name().into())),
+        other => Err(RuntimeError::type_mismatch(format!(
+            "symbol->string expects a symbol, found {}",
+            other
+        ))),
+    }
+}
+
+fn string_to_symbol(args: &[Value]) -> Result<Value, RuntimeError> {
+    match &args[0] {
+        Value::String(name) => Ok(Value::Symbol(Symbol::intern(name))),
+        other => Err(RuntimeError::type_mismatch(format!(
+            "string->symbol expects a string, found {}",
+            other
+        ))),
+    }
+}
+
+fn error(args: &[Value]) -> Result<Value, RuntimeError> {
+    // The message and irritants are joined like display would print them
+    let message = args
+        .iter()
+        .map(|arg| match arg {
+            Value::String(v) => v.to_string(),
+            other => other.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join(" ");
+    Err(RuntimeError::new(
+        RuntimeErrorKind::User(args[1..].into()),
+        message,
+    ))
+}
+
+fn raise(args: &[Value]) -> Result<Value, RuntimeError> {
+    match &args[0] {
+        Value::Error(err) => Err(RuntimeError::clone(err)),
+        other => Err(RuntimeError::new(
+            RuntimeErrorKind::Raised(Box::new(other.clone())),
+            format!("Uncaught raise of {}", other),
+        )),
+    }
+}
+
+fn is_error(args: &[Value]) -> Result<Value, RuntimeError> {
+    Ok(Value::Boolean(matches!(args[0], Value::Error(_))))
+}
+
+fn error_message(args: &[Value]) -> Result<Value, RuntimeError> {
+    let err = expect_error("error-message", &args[0])?;
+    Ok(Value::String(err.message.as_str().into()))
+}
+
+fn error_kind(args: &[Value]) -> Result<Value, RuntimeError> {
+    let err = expect_error("error-kind", &args[0])?;
+    Ok(Value::Symbol(Symbol::intern(err.kind.name())))
+}
+
+fn error_irritants(args: &[Value]) -> Result<Value, RuntimeError> {
+    match &expect_error("error-irritants", &args[0])?.kind {
+        RuntimeErrorKind::User(irritants) => Ok(Value::list(irritants.iter().cloned())),
+        _ => Ok(Value::Nil),
+    }
 }
@@ -1,21 +1,92 @@
+use crate::builtin::register_builtins;
+use crate::errors::{RuntimeError, RuntimeErrorKind};
 use crate::value::Value;
+use std::cell::RefCell;
 use std::collections::HashMap;
+use std::fmt;
+use std::rc::Rc;
 
-pub struct Environment {
+#[derive(Default)]
+struct Frame {
     variables: HashMap<String, Value>,
+    parent: Option<Environment>,
+}
+
+/// A handle to a scope. Cloning it is cheap and shares the underlying frame,
+/// which is how closures keep their defining scope alive.
+#[derive(Default, Clone)]
+pub struct Environment {
+    frame: Rc<RefCell<Frame>>,
 }
 impl Environment {
     pub fn new() -> Self {
-        Self {
+        Self::default()
+    }
+
+    pub fn global() -> Self {
+        let env = Self::new();
+        env.define_var("pi", Value::Float(std::f64::consts::PI));
+        env.define_var("inf", Value::Float(f64::INFINITY));
+        env.define_var("nan", Value::Float(f64::NAN));
+        register_builtins(&env);
+        env
+    }
+
+    /// Creates a new, empty scope whose lookups fall back to `self`.
+    pub fn extend(&self) -> Self {
+        let frame = Frame {
             variables: HashMap::new(),
+            parent: Some(self.clone()),
+        };
+        Self {
+            frame: Rc::new(RefCell::new(frame)),
         }
     }
 
-    pub fn set_var(&mut self, name: &String, val: Value) {
-        self.variables.insert(name.to_string(), val);
+    /// Binds `name` in the current frame, shadowing any binding in a parent frame.
+    pub fn define_var(&self, name: &str, val: Value) {
+        self.frame
+            .borrow_mut()
+            .variables
+            .insert(name.to_string(), val);
+    }
+
+    /// Rebinds the nearest existing binding of `name`.
+    pub fn set_var(&self, name: &str, val: Value) -> Result<(), RuntimeError> {
+        let mut frame = self.frame.borrow_mut();
+        if let Some(slot) = frame.variables.get_mut(name) {
+            *slot = val;
+            return Ok(());
+        }
+        match &frame.parent {
+            Some(parent) => parent.set_var(name, val),
+            None => Err(RuntimeError::new(
+                RuntimeErrorKind::UnboundVariable(name.to_string()),
+                format!("Cannot set unbound variable '{}'", name),
+            )
+            .with_note("use define to introduce a new variable")),
+        }
     }
 
-    pub fn get_var(&mut self, name: &String) -> Option<&Value> {
-        self.variables.get(name)
+    pub fn get_var(&self, name: &str) -> Option<Value> {
+        let frame = self.frame.borrow();
+        match frame.variables.get(name) {
+            Some(val) => Some(val.clone()),
+            None => frame
+                .parent
+                .as_ref()
+                .and_then(|parent| parent.get_var(name)),
+        }
+    }
+}
+
+impl fmt::Debug for Environment {
+    // Frames can contain closures that point back at them, so only the names are printed
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let frame = self.frame.borrow();
+        f.debug_struct("Environment")
+            .field("variables", &frame.variables.keys().collect::<Vec<_>>())
+            .field("parent", &frame.parent)
+            .finish()
     }
 }
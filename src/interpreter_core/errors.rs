@@ -1,23 +1,125 @@
 use std::fmt;
+use std::rc::Rc;
 
+use crate::span::Span;
 use crate::token;
+use crate::value::Value;
 
 #[derive(Debug)]
 pub struct TokenizingError {
     pub line_number: usize,
+    /// Column of the token in chars
     pub token_index: usize,
+    /// Column of the offending char, the report underlines everything from the token up to it
     pub error_index: usize,
     pub message: String,
+    /// The source ended inside a string, a |symbol| or a block comment, so more input could
+    /// still complete it
+    pub unclosed: bool,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
+pub enum RuntimeErrorKind {
+    /// A function was called with the wrong number of arguments
+    Arity,
+    /// A value of the wrong type was passed to an operation
+    TypeMismatch,
+    /// Carries the name of the variable
+    UnboundVariable(String),
+    DivisionByZero,
+    /// An integer result does not fit into 64 bits
+    Overflow,
+    /// A value of the right type is outside of what an operation accepts, e.g. (car '())
+    InvalidArgument,
+    /// A special form is malformed, e.g. (define 1 2)
+    Syntax,
+    RecursionLimit,
+    /// Signalled by (error message irritants...), carries the irritants
+    User(Box<[Value]>),
+    /// Carries a value passed to (raise value) that is not itself an error
+    Raised(Box<Value>),
+}
+
+impl RuntimeErrorKind {
+    pub fn code(&self) -> &'static str {
+        match self {
+            RuntimeErrorKind::Arity => "E0101",
+            RuntimeErrorKind::TypeMismatch => "E0102",
+            RuntimeErrorKind::UnboundVariable(_) => "E0103",
+            RuntimeErrorKind::DivisionByZero => "E0104",
+            RuntimeErrorKind::Overflow => "E0105",
+            RuntimeErrorKind::InvalidArgument => "E0106",
+            RuntimeErrorKind::Syntax => "E0107",
+            RuntimeErrorKind::RecursionLimit => "E0108",
+            RuntimeErrorKind::User(_) => "E0109",
+            RuntimeErrorKind::Raised(_) => "E0110",
+        }
+    }
+
+    /// The name Lisp code uses for the kind, as returned by error-kind and matched by catch.
+    pub fn name(&self) -> &'static str {
+        match self {
+            RuntimeErrorKind::Arity => "arity",
+            RuntimeErrorKind::TypeMismatch => "type-mismatch",
+            RuntimeErrorKind::UnboundVariable(_) => "unbound-variable",
+            RuntimeErrorKind::DivisionByZero => "division-by-zero",
+            RuntimeErrorKind::Overflow => "overflow",
+            RuntimeErrorKind::InvalidArgument => "invalid-argument",
+            RuntimeErrorKind::Syntax => "syntax",
+            RuntimeErrorKind::RecursionLimit => "recursion-limit",
+            RuntimeErrorKind::User(_) => "error",
+            RuntimeErrorKind::Raised(_) => "raised",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct RuntimeError {
+    pub kind: RuntimeErrorKind,
     pub message: String,
+    pub span: Option<Span>,
+    pub notes: Vec<String>,
 }
 
 impl RuntimeError {
-    pub fn new(msg: String) -> RuntimeError {
-        RuntimeError { message: msg }
+    pub fn new(kind: RuntimeErrorKind, msg: String) -> RuntimeError {
+        RuntimeError {
+            kind,
+            message: msg,
+            span: None,
+            notes: Vec::new(),
+        }
+    }
+
+    pub fn arity(msg: String) -> RuntimeError {
+        RuntimeError::new(RuntimeErrorKind::Arity, msg)
+    }
+
+    pub fn type_mismatch(msg: String) -> RuntimeError {
+        RuntimeError::new(RuntimeErrorKind::TypeMismatch, msg)
+    }
+
+    pub fn syntax(msg: String) -> RuntimeError {
+        RuntimeError::new(RuntimeErrorKind::Syntax, msg)
+    }
+
+    pub fn with_span(mut self, span: Span) -> RuntimeError {
+        self.span = Some(span);
+        self
+    }
+
+    pub fn with_note(mut self, note: &str) -> RuntimeError {
+        self.notes.push(note.to_string());
+        self
+    }
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.span {
+            Some(span) => write!(f, "{}: {}", span, self.message),
+            None => write!(f, "{}", self.message),
+        }
     }
 }
 #[derive(Debug)]
@@ -36,12 +138,59 @@ impl EvalError {
     }
 }
 
+impl EvalError {
+    /// Builds a diagnostic for the error, `source_name` is used when the error does not carry
+    /// a file name itself.
+    pub fn diagnostic(&self, source_name: Option<Rc<str>>) -> Diagnostic {
+        match self {
+            EvalError::Tokenizing(err) => {
+                let length = err.error_index.saturating_sub(err.token_index) + 1;
+                let span = Span {
+                    file: source_name,
+                    line: err.line_number + 1,
+                    column: err.token_index + 1,
+                    length,
+                };
+                Diagnostic::new("E0001", &err.message, Some(span))
+            }
+            EvalError::Parsing(err) => {
+                let span = err
+                    .token
+                    .as_ref()
+                    .map(|token| Span::from_context(&token.context, source_name));
+                Diagnostic::new("E0002", &err.message, span)
+            }
+            EvalError::Runtime(err) => {
+                let span = err.span.clone().map(|mut span| {
+                    span.file = span.file.or(source_name);
+                    span
+                });
+                let mut diagnostic = Diagnostic::new(err.kind.code(), &err.message, span);
+                diagnostic.notes = err.notes.clone();
+                diagnostic
+            }
+        }
+    }
+}
+
+impl fmt::Display for EvalError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EvalError::Tokenizing(err) => write!(f, "{}", err),
+            EvalError::Parsing(err) => write!(f, "{}", err),
+            EvalError::Runtime(err) => write!(f, "{}", err),
+        }
+    }
+}
+
 impl fmt::Display for TokenizingError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(
             f,
-            "Error on line {}, column {}, error index: {}: {}", // ToDo Improve error messages
-            self.line_number, self.token_index, self.error_index, self.message
+            "line {}, column {}: {}",
+            self.line_number + 1,
+            self.error_index + 1,
+            self.message
         )
     }
 }
@@ -53,31 +202,145 @@ impl TokenizingError {
             token_index,
             error_index,
             message: message.to_string(),
+            unclosed: false,
         }
     }
 }
 
 #[derive(Debug)]
 pub struct ParsingError {
-    pub token: Option<token::Token>,
+    /// Boxed, tokens can carry large numbers
+    pub token: Option<Box<token::Token>>,
     pub message: String,
 }
 
 impl fmt::Display for ParsingError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(
-            f,
-            "Parsing Error: {}", // ToDo Improve error messages
-            self.message
-        )
+        match &self.token {
+            Some(token) => write!(
+                f,
+                "line {}, column {}: {}",
+                token.context.line_number + 1,
+                token.context.column_number + 1,
+                self.message
+            ),
+            None => write!(f, "{}", self.message),
+        }
     }
 }
 
 impl ParsingError {
     pub fn new(token: Option<token::Token>, message: &str) -> Self {
         ParsingError {
-            token,
+            token: token.map(Box::new),
+            message: message.to_string(),
+        }
+    }
+}
+
+/// A rustc-style report of an error, rendered with the offending source line and a caret
+/// underline, e.g.
+///
+/// ```text
+/// error[E0103]: Unbound variable 'y'
+///  --> main.lisp:1:6
+///   |
+/// 1 | (+ 1 y)
+///   |      ^
+/// ```
+#[derive(Debug)]
+pub struct Diagnostic {
+    pub code: &'static str,
+    pub message: String,
+    pub span: Option<Span>,
+    pub notes: Vec<String>,
+}
+
+const RED: &str = "\x1b[1;31m";
+const BLUE: &str = "\x1b[1;34m";
+const BOLD: &str = "\x1b[1m";
+const RESET: &str = "\x1b[0m";
+
+/// Tabs in source excerpts are expanded to this many spaces
+const TAB_WIDTH: usize = 4;
+
+/// The number of columns `chars` take up once tabs are expanded
+fn display_width(chars: impl Iterator<Item = char>) -> usize {
+    chars.map(|c| if c == '\t' { TAB_WIDTH } else { 1 }).sum()
+}
+
+impl Diagnostic {
+    pub fn new(code: &'static str, message: &str, span: Option<Span>) -> Self {
+        Diagnostic {
+            code,
             message: message.to_string(),
+            span,
+            notes: Vec::new(),
+        }
+    }
+
+    pub fn render(&self, source: &str, color: bool) -> String {
+        let paint = |style: &str, text: &str| {
+            if color {
+                format!("{}{}{}", style, text, RESET)
+            } else {
+                text.to_string()
+            }
+        };
+
+        let mut out = format!(
+            "{}{}\n",
+            paint(RED, &format!("error[{}]", self.code)),
+            paint(BOLD, &format!(": {}", self.message))
+        );
+
+        let mut gutter_width = 1;
+        if let Some(span) = &self.span {
+            let line_number = span.line.to_string();
+            gutter_width = line_number.len() + 1;
+            let gutter = " ".repeat(gutter_width);
+            let location = match &span.file {
+                Some(file) => format!("{}:{}:{}", file, span.line, span.column),
+                None => format!("<input>:{}:{}", span.line, span.column),
+            };
+            out.push_str(&format!(
+                "{}{} {}\n",
+                " ".repeat(gutter_width - 1),
+                paint(BLUE, "-->"),
+                location
+            ));
+
+            if let Some(line) = source.lines().nth(span.line.saturating_sub(1)) {
+                let offset = span.column.saturating_sub(1).min(line.chars().count());
+                let available = line.chars().count().saturating_sub(offset).max(1);
+                let length = span.length.clamp(1, available);
+                // Tabs are expanded in both the line and the underline so that they line up
+                // however wide the terminal renders a tab
+                let padding = display_width(line.chars().take(offset));
+                let carets = display_width(line.chars().skip(offset).take(length)).max(1);
+
+                out.push_str(&format!("{}\n", paint(BLUE, &format!("{}|", gutter))));
+                out.push_str(&format!(
+                    "{} {}\n",
+                    paint(BLUE, &format!("{} |", line_number)),
+                    line.replace('\t', &" ".repeat(TAB_WIDTH))
+                ));
+                out.push_str(&format!(
+                    "{} {}{}\n",
+                    paint(BLUE, &format!("{}|", gutter)),
+                    " ".repeat(padding),
+                    paint(RED, &"^".repeat(carets))
+                ));
+            }
+        }
+
+        for note in &self.notes {
+            out.push_str(&format!(
+                "{} {}\n",
+                paint(BLUE, &format!("{}=", " ".repeat(gutter_width))),
+                paint(BOLD, &format!("help: {}", note))
+            ));
         }
+        out
     }
 }
@@ -1,232 +1,778 @@
-use itertools::Itertools;
-
 use crate::ast::AstNode;
 use crate::ast::AstNodeValue;
+use crate::builtin;
 use crate::env::Environment;
-use crate::errors::{EvalError, RuntimeError};
+use crate::errors::{EvalError, RuntimeError, RuntimeErrorKind};
 use crate::operatortype::Operator;
 use crate::parse::parse;
+use crate::symbol::Symbol;
 use crate::tokenize::tokenize;
-use crate::value::Value;
+use crate::trace;
+use crate::value::{self, Closure, Value};
+use std::cell::Cell;
+use std::rc::Rc;
 
-fn eval_plus(node: &AstNode, env: &mut Environment) -> Result<Value, RuntimeError> {
-    let mut value = Value::Int(0);
-    for child in node.children() {
-        value += eval_tree(child, env)?;
+/// Operators in head position take a fast path that applies them natively, as long as their
+/// name is still bound to the builtin, e.g. not after (define + -) or in (let ((+ *)) ...).
+/// Either way every argument is evaluated first, like for any other call.
+fn eval_operator(
+    node: &AstNode,
+    op: &Operator,
+    env: &mut Environment,
+) -> Result<Value, RuntimeError> {
+    let name = op.to_string();
+    let function = env.get_var(&name);
+    let args = eval_all(node.children(), env)?;
+    match function {
+        Some(Value::Builtin(builtin)) if builtin::is_global(builtin, &name) => op.apply(&args),
+        Some(function) => apply(&function, args),
+        None => op.apply(&args),
     }
-    Ok(value)
 }
 
-fn eval_minus(node: &AstNode, env: &mut Environment) -> Result<Value, RuntimeError> {
-    let mut value = eval_tree(node.children().get(0).unwrap(), env)?;
-    if node.children().len() == 1 {
-        value.negate();
-        return Ok(value);
+fn eval_identifier(identifier: &String, env: &mut Environment) -> Result<Value, RuntimeError> {
+    match env.get_var(identifier) {
+        Some(val) => Ok(val),
+        None => Err(RuntimeError::new(
+            RuntimeErrorKind::UnboundVariable(identifier.clone()),
+            format!("Unbound variable '{}'", identifier),
+        )
+        .with_note("variables have to be defined with (define name value) before use")),
     }
-    for child in node.children().iter().skip(1) {
-        value -= eval_tree(child, env)?;
+}
+
+fn eval_define(node: &AstNode, env: &mut Environment) -> Result<Value, RuntimeError> {
+    let children = node.children();
+    if children.len() != 3 {
+        return Err(RuntimeError::syntax(format!(
+            "define expects a name and a value, got {} argument(s)",
+            children.len() - 1
+        )));
     }
+
+    let name = match &children[1].value {
+        AstNodeValue::Identifier(name) => name,
+        _ => {
+            return Err(RuntimeError::syntax(format!(
+                "define expects an identifier as its name, found {}",
+                source_form(&children[1])
+            )))
+        }
+    };
+
+    let value = eval_tree(&children[2], env)?;
+    env.define_var(name, value.clone());
     Ok(value)
 }
 
-fn eval_mul(node: &AstNode, env: &mut Environment) -> Result<Value, RuntimeError> {
-    let mut value = Value::Int(1);
-    for child in node.children() {
-        value *= eval_tree(child, env)?;
+fn eval_set(node: &AstNode, env: &mut Environment) -> Result<Value, RuntimeError> {
+    let children = node.children();
+    if children.len() != 3 {
+        return Err(RuntimeError::syntax(format!(
+            "set! expects a name and a value, got {} argument(s)",
+            children.len() - 1
+        )));
     }
+
+    let name = match &children[1].value {
+        AstNodeValue::Identifier(name) => name,
+        _ => {
+            return Err(RuntimeError::syntax(format!(
+                "set! expects an identifier as its name, found {}",
+                source_form(&children[1])
+            )))
+        }
+    };
+
+    let value = eval_tree(&children[2], env)?;
+    env.set_var(name, value.clone())?;
     Ok(value)
 }
 
-fn eval_modulo(node: &AstNode, env: &mut Environment) -> Result<Value, RuntimeError> {
-    let mut value = eval_tree(node.children().get(0).unwrap(), env)?;
-    for child in node.children().iter().skip(1) {
-        value %= eval_tree(child, env)?;
+fn parse_params(node: &AstNode) -> Result<Vec<String>, RuntimeError> {
+    let mut params = Vec::new();
+    match &node.value {
+        AstNodeValue::Expression => {}
+        // A list starting with an operator, like (+ x), is parsed as an operator application
+        AstNodeValue::Operator(op) => params.push(op.to_string()),
+        _ => {
+            return Err(RuntimeError::syntax(format!(
+                "Expected a parameter list, found {}",
+                source_form(node)
+            )))
+        }
     }
-    Ok(value)
+
+    for child in node.children() {
+        match &child.value {
+            AstNodeValue::Identifier(name) => params.push(name.clone()),
+            _ => {
+                return Err(RuntimeError::syntax(format!(
+                    "Parameter names must be identifiers, found {}",
+                    source_form(child)
+                )))
+            }
+        }
+    }
+    Ok(params)
 }
 
-fn eval_div(node: &AstNode, env: &mut Environment) -> Result<Value, RuntimeError> {
-    let mut value = eval_tree(node.children().get(0).unwrap(), env)?;
-    for child in node.children().iter().skip(1) {
-        value /= eval_tree(child, env)?;
+fn make_closure(
+    name: Option<String>,
+    params_node: &AstNode,
+    body: &[AstNode],
+    env: &Environment,
+) -> Result<Value, RuntimeError> {
+    if body.is_empty() {
+        return Err(RuntimeError::syntax(
+            "Function body must not be empty".to_string(),
+        ));
     }
-    Ok(value)
+    let closure = Closure {
+        name,
+        params: parse_params(params_node)?,
+        body: body.to_vec(),
+        env: env.clone(),
+    };
+    Ok(Value::Closure(Rc::new(closure)))
 }
 
-fn eval_intdiv(node: &AstNode, env: &mut Environment) -> Result<Value, RuntimeError> {
-    let mut value = eval_tree(node.children().get(0).unwrap(), env)?;
-    for child in node.children().iter().skip(1) {
-        value.int_div_assign(eval_tree(child, env)?);
+fn eval_lambda(node: &AstNode, env: &mut Environment) -> Result<Value, RuntimeError> {
+    let children = node.children();
+    if children.len() < 3 {
+        return Err(RuntimeError::syntax(
+            "lambda expects a parameter list and a body".to_string(),
+        ));
     }
-    Ok(value)
+    make_closure(None, &children[1], &children[2..], env)
 }
 
-fn eval_pow(node: &AstNode, env: &mut Environment) -> Result<Value, RuntimeError> {
-    let mut value = eval_tree(node.children().get(0).unwrap(), env)?;
-    for child in node.children().iter().skip(1) {
-        value.pow_assign(eval_tree(child, env)?);
+fn eval_defun(node: &AstNode, env: &mut Environment) -> Result<Value, RuntimeError> {
+    let children = node.children();
+    if children.len() < 4 {
+        return Err(RuntimeError::syntax(
+            "defun expects a name, a parameter list and a body".to_string(),
+        ));
     }
-    Ok(value)
+
+    let name = match &children[1].value {
+        AstNodeValue::Identifier(name) => name,
+        _ => {
+            return Err(RuntimeError::syntax(format!(
+                "defun expects an identifier as its name, found {}",
+                source_form(&children[1])
+            )))
+        }
+    };
+
+    let closure = make_closure(Some(name.clone()), &children[2], &children[3..], env)?;
+    env.define_var(name, closure.clone());
+    Ok(closure)
 }
 
-fn eval_eq(node: &AstNode, env: &mut Environment) -> Result<Value, RuntimeError> {
-    let value = eval_tree(node.children().get(0).unwrap(), env)?;
-    for child in node.children().iter().skip(1) {
-        if eval_tree(child, env)? != value {
-            return Ok(Value::Boolean(false));
+fn apply_closure(closure: &Rc<Closure>, args: Vec<Value>) -> Result<Value, RuntimeError> {
+    if args.len() != closure.params.len() {
+        return Err(RuntimeError::arity(format!(
+            "{} expects {} argument(s), got {}",
+            Value::Closure(closure.clone()),
+            closure.params.len(),
+            args.len()
+        )));
+    }
+
+    let depth = CALL_DEPTH.with(|depth| depth.get());
+    let limit = RECURSION_LIMIT.with(|limit| limit.get());
+    if depth >= limit {
+        return Err(RuntimeError::new(
+            RuntimeErrorKind::RecursionLimit,
+            format!("Maximum recursion depth of {} exceeded", limit),
+        ));
+    }
+
+    let mut local = closure.env.extend();
+    for (param, arg) in closure.params.iter().zip(args) {
+        local.define_var(param, arg);
+    }
+
+    CALL_DEPTH.with(|d| d.set(depth + 1));
+    let result = eval_body(&closure.body, &mut local);
+    CALL_DEPTH.with(|d| d.set(depth));
+    result
+}
+
+fn eval_body(body: &[AstNode], env: &mut Environment) -> Result<Value, RuntimeError> {
+    let mut result = Value::None;
+    for expr in body {
+        result = eval_tree(expr, env)?;
+    }
+    Ok(result)
+}
+
+#[derive(Clone, Copy)]
+enum LetKind {
+    Let,
+    LetStar,
+    LetRec,
+}
+
+fn parse_bindings(node: &AstNode) -> Result<Vec<(String, &AstNode)>, RuntimeError> {
+    if !matches!(node.value, AstNodeValue::Expression) {
+        return Err(RuntimeError::syntax(format!(
+            "Expected a binding list, found {}",
+            source_form(node)
+        )));
+    }
+
+    let mut bindings = Vec::new();
+    for binding in node.children() {
+        let children = binding.children();
+        match (&binding.value, children.len()) {
+            (AstNodeValue::Expression, 2) => match &children[0].value {
+                AstNodeValue::Identifier(name) => bindings.push((name.clone(), &children[1])),
+                _ => {
+                    return Err(RuntimeError::syntax(format!(
+                        "Binding names must be identifiers, found {}",
+                        source_form(&children[0])
+                    )))
+                }
+            },
+            // (+ *) binds + but was parsed as an operator application
+            (AstNodeValue::Operator(op), 1) => bindings.push((op.to_string(), &children[0])),
+            _ => {
+                return Err(RuntimeError::syntax(format!(
+                    "Bindings must be of the form (name value), found {}",
+                    source_form(binding)
+                )))
+            }
         }
     }
-    Ok(Value::Boolean(true))
+    Ok(bindings)
 }
 
-fn eval_lt(node: &AstNode, env: &mut Environment) -> Result<Value, RuntimeError> {
-    for (a, b) in node.children().iter().tuple_windows() {
-        let (left, right) = (eval_tree(a, env)?, eval_tree(b, env)?);
-        if left.compare_to(&right)? >= 0 {
-            return Ok(Value::Boolean(false));
+fn eval_let(node: &AstNode, env: &mut Environment, kind: LetKind) -> Result<Value, RuntimeError> {
+    let children = node.children();
+    if children.len() < 3 {
+        return Err(RuntimeError::syntax(
+            "let expects a binding list and a body".to_string(),
+        ));
+    }
+
+    let bindings = parse_bindings(&children[1])?;
+    let mut local = env.extend();
+    match kind {
+        // All values are computed in the outer scope before any of them is bound
+        LetKind::Let => {
+            let mut values = Vec::new();
+            for (_, expr) in &bindings {
+                values.push(eval_tree(expr, env)?);
+            }
+            for ((name, _), value) in bindings.iter().zip(values) {
+                local.define_var(name, value);
+            }
+        }
+        LetKind::LetStar => {
+            for (name, expr) in &bindings {
+                let value = eval_tree(expr, &mut local)?;
+                local.define_var(name, value);
+            }
         }
+        // Every name is visible to every value, so lambdas can refer to each other
+        LetKind::LetRec => {
+            for (name, _) in &bindings {
+                local.define_var(name, Value::None);
+            }
+            for (name, expr) in &bindings {
+                let value = eval_tree(expr, &mut local)?;
+                local.define_var(name, value);
+            }
+        }
+    }
+
+    eval_body(&children[2..], &mut local)
+}
+
+fn eval_if(node: &AstNode, env: &mut Environment) -> Result<Value, RuntimeError> {
+    let children = node.children();
+    if !(3..=4).contains(&children.len()) {
+        return Err(RuntimeError::syntax(format!(
+            "if expects a condition, a consequent and an optional alternative, got {} argument(s)",
+            children.len() - 1
+        )));
+    }
+
+    if eval_tree(&children[1], env)?.is_truthy() {
+        eval_tree(&children[2], env)
+    } else if let Some(alternative) = children.get(3) {
+        eval_tree(alternative, env)
+    } else {
+        Ok(Value::None)
     }
-    Ok(Value::Boolean(true))
 }
 
-fn eval_leq(node: &AstNode, env: &mut Environment) -> Result<Value, RuntimeError> {
-    for (a, b) in node.children().iter().tuple_windows() {
-        let (left, right) = (eval_tree(a, env)?, eval_tree(b, env)?);
-        if left.compare_to(&right)? > 0 {
-            return Ok(Value::Boolean(false));
+fn eval_cond(node: &AstNode, env: &mut Environment) -> Result<Value, RuntimeError> {
+    for clause in node.children().iter().skip(1) {
+        if !matches!(clause.value, AstNodeValue::Expression) || clause.children().is_empty() {
+            return Err(RuntimeError::syntax(format!(
+                "cond clauses must be of the form (test body...), found {}",
+                source_form(clause)
+            )));
+        }
+
+        let (test, body) = clause.children().split_first().unwrap();
+        let test_value = match &test.value {
+            AstNodeValue::Identifier(name) if name == "else" => Value::Boolean(true),
+            _ => eval_tree(test, env)?,
+        };
+        if test_value.is_truthy() {
+            if body.is_empty() {
+                return Ok(test_value);
+            }
+            return eval_body(body, env);
         }
     }
-    Ok(Value::Boolean(true))
+    Ok(Value::None)
+}
+
+fn eval_when(node: &AstNode, env: &mut Environment, expected: bool) -> Result<Value, RuntimeError> {
+    let children = node.children();
+    if children.len() < 2 {
+        return Err(RuntimeError::syntax(
+            "when/unless expect a condition and a body".to_string(),
+        ));
+    }
+
+    if eval_tree(&children[1], env)?.is_truthy() == expected {
+        eval_body(&children[2..], env)
+    } else {
+        Ok(Value::None)
+    }
 }
 
-fn eval_gt(node: &AstNode, env: &mut Environment) -> Result<Value, RuntimeError> {
-    for (a, b) in node.children().iter().tuple_windows() {
-        let (left, right) = (eval_tree(a, env)?, eval_tree(b, env)?);
-        if left.compare_to(&right)? <= 0 {
-            return Ok(Value::Boolean(false));
+fn eval_and(node: &AstNode, env: &mut Environment) -> Result<Value, RuntimeError> {
+    let mut value = Value::Boolean(true);
+    for child in node.children().iter().skip(1) {
+        value = eval_tree(child, env)?;
+        if !value.is_truthy() {
+            return Ok(value);
         }
     }
-    Ok(Value::Boolean(true))
+    Ok(value)
 }
 
-fn eval_geq(node: &AstNode, env: &mut Environment) -> Result<Value, RuntimeError> {
-    for (a, b) in node.children().iter().tuple_windows() {
-        let (left, right) = (eval_tree(a, env)?, eval_tree(b, env)?);
-        if left.compare_to(&right)? < 0 {
-            return Ok(Value::Boolean(false));
+fn eval_or(node: &AstNode, env: &mut Environment) -> Result<Value, RuntimeError> {
+    let mut value = Value::Boolean(false);
+    for child in node.children().iter().skip(1) {
+        value = eval_tree(child, env)?;
+        if value.is_truthy() {
+            return Ok(value);
         }
     }
-    Ok(Value::Boolean(true))
+    Ok(value)
 }
 
-fn eval_neq(node: &AstNode, env: &mut Environment) -> Result<Value, RuntimeError> {
-    let mut vals: Vec<Value> = Vec::new();
-    for child in node.children() {
-        let val = eval_tree(child, env)?;
-        vals.push(val);
+fn eval_not(node: &AstNode, env: &mut Environment) -> Result<Value, RuntimeError> {
+    let children = node.children();
+    if children.len() != 2 {
+        return Err(RuntimeError::syntax(format!(
+            "not expects exactly one argument, got {}",
+            children.len() - 1
+        )));
+    }
+    Ok(Value::Boolean(!eval_tree(&children[1], env)?.is_truthy()))
+}
+
+/// A (catch [kind] (var) handler...) clause of try
+struct CatchClause<'a> {
+    kind: Option<&'a str>,
+    var: String,
+    body: &'a [AstNode],
+}
+
+fn try_clause_name(node: &AstNode) -> Option<&str> {
+    if !matches!(node.value, AstNodeValue::Expression) {
+        return None;
+    }
+    match node.children().first().map(|head| &head.value) {
+        Some(AstNodeValue::Identifier(name)) if name == "catch" || name == "finally" => Some(name),
+        _ => None,
     }
+}
 
-    for (a, b) in vals.iter().tuple_combinations() {
-        if a.compare_to(b)? == 0 {
-            return Ok(Value::Boolean(false));
+fn parse_catch(node: &AstNode) -> Result<CatchClause<'_>, RuntimeError> {
+    let children = &node.children()[1..];
+    let (kind, rest) = match children.first().map(|child| &child.value) {
+        Some(AstNodeValue::Identifier(kind)) => (Some(kind.as_str()), &children[1..]),
+        _ => (None, children),
+    };
+    if let Some((params, body)) = rest.split_first() {
+        if let Ok([var]) = <[String; 1]>::try_from(parse_params(params)?) {
+            return Ok(CatchClause { kind, var, body });
         }
     }
-    Ok(Value::Boolean(true))
+    Err(RuntimeError::syntax(
+        "catch clauses must be of the form (catch [kind] (var) handler...)".to_string(),
+    ))
 }
 
-fn eval_operator(
-    node: &AstNode,
-    op: &Operator,
+fn eval_catch(
+    catch: &CatchClause,
+    err: RuntimeError,
     env: &mut Environment,
 ) -> Result<Value, RuntimeError> {
-    match *op {
-        Operator::Plus => eval_plus(node, env),
-        Operator::Minus => eval_minus(node, env),
-        Operator::Mul => eval_mul(node, env),
-        Operator::Modulo => eval_modulo(node, env),
-        Operator::Div => eval_div(node, env),
-        Operator::IntDiv => eval_intdiv(node, env),
-        Operator::Power => eval_pow(node, env),
-        Operator::Eq => eval_eq(node, env),
-        Operator::Lt => eval_lt(node, env),
-        Operator::Leq => eval_leq(node, env),
-        Operator::Gt => eval_gt(node, env),
-        Operator::Geq => eval_geq(node, env),
-        Operator::Neq => eval_neq(node, env),
-        //_ => unimplemented!("Operator not implemented"),
-    }
-}
-
-fn eval_identifier(
-    node: &AstNode,
-    identifier: &String,
+    // Raised values are handed back as they are, everything else as an error value
+    let value = match &err.kind {
+        RuntimeErrorKind::Raised(value) => Value::clone(value),
+        _ => Value::Error(Rc::new(err)),
+    };
+    let mut local = env.extend();
+    local.define_var(&catch.var, value);
+    eval_body(catch.body, &mut local)
+}
+
+/// Runs `cleanup` regardless of `result`, an error in the cleanup replaces the result.
+fn unwind(
+    result: Result<Value, RuntimeError>,
+    cleanup: &[AstNode],
     env: &mut Environment,
-) -> Result<AstNode, RuntimeError> {
-    let mut new_node = node.clone();
-    if let Some(val) = env.get_var(identifier) {
-        let mut flattened_subtree = AstNode::new(AstNodeValue::Values);
-        flattened_subtree.add_child(AstNode::new(AstNodeValue::Literal(val.clone())));
-        flattened_subtree.append_children(node.children().clone());
-        println!("{}", flattened_subtree);
-        return Ok(flattened_subtree);
+) -> Result<Value, RuntimeError> {
+    eval_body(cleanup, env)?;
+    result
+}
+
+fn eval_try(node: &AstNode, env: &mut Environment) -> Result<Value, RuntimeError> {
+    let children = &node.children()[1..];
+    let body_len = children
+        .iter()
+        .position(|child| try_clause_name(child).is_some())
+        .unwrap_or(children.len());
+    let (body, clauses) = children.split_at(body_len);
+
+    let mut catches = Vec::new();
+    let mut cleanup: &[AstNode] = &[];
+    for (i, clause) in clauses.iter().enumerate() {
+        match try_clause_name(clause) {
+            Some("catch") => catches.push(parse_catch(clause)?),
+            Some("finally") if i == clauses.len() - 1 => cleanup = &clause.children()[1..],
+            _ => {
+                return Err(RuntimeError::syntax(
+                    "try expects (catch ...) clauses after its body and (finally ...) last"
+                        .to_string(),
+                ))
+            }
+        }
+    }
+
+    let result = match eval_body(body, env) {
+        Err(err) => {
+            let kind = err.kind.name();
+            match catches
+                .iter()
+                .find(|catch| catch.kind.is_none_or(|k| k == kind))
+            {
+                Some(catch) => eval_catch(catch, err, env),
+                None => Err(err),
+            }
+        }
+        ok => ok,
+    };
+    unwind(result, cleanup, env)
+}
+
+fn eval_unwind_protect(node: &AstNode, env: &mut Environment) -> Result<Value, RuntimeError> {
+    let children = node.children();
+    if children.len() < 2 {
+        return Err(RuntimeError::syntax(
+            "unwind-protect expects a protected form".to_string(),
+        ));
     }
+    let result = eval_tree(&children[1], env);
+    unwind(result, &children[2..], env)
+}
+
+fn quote(node: &AstNode) -> Result<Value, RuntimeError> {
+    let value = match &node.value {
+        AstNodeValue::Literal(v) => v.clone(),
+        AstNodeValue::Identifier(name) => Value::Symbol(Symbol::intern(name)),
+        AstNodeValue::Expression => quote_list(Vec::new(), node.children())?,
+        AstNodeValue::Operator(op) => {
+            let head = Value::Symbol(Symbol::intern(&op.to_string()));
+            quote_list(vec![head], node.children())?
+        }
+        // ''x is the same as '(quote x)
+        AstNodeValue::Quote => {
+            let head = Value::Symbol(Symbol::intern("quote"));
+            quote_list(vec![head], node.children())?
+        }
+        AstNodeValue::DottedTail => return Err(dotted_outside_quote()),
+        AstNodeValue::Root => {
+            return Err(RuntimeError::syntax(
+                "Cannot quote a program root".to_string(),
+            ))
+        }
+    };
+    Ok(value)
+}
+
+/// Quotes `children` as the elements of a list after `items`, the list ends in the datum after
+/// the dot if it was written as e.g. '(1 . 2)
+fn quote_list(mut items: Vec<Value>, children: &[AstNode]) -> Result<Value, RuntimeError> {
+    let mut tail = Value::Nil;
+    for child in children {
+        match child.value {
+            AstNodeValue::DottedTail => tail = quote(&child.children()[0])?,
+            _ => items.push(quote(child)?),
+        }
+    }
+    Ok(items
+        .into_iter()
+        .rev()
+        .fold(tail, |tail, item| Value::cons(item, tail)))
+}
+
+fn dotted_outside_quote() -> RuntimeError {
+    RuntimeError::syntax("Cannot evaluate a dotted list".to_string())
+        .with_note("only quoted data can be dotted lists, e.g. '(1 . 2)")
+}
+
+/// How `node` was written, for error messages
+fn source_form(node: &AstNode) -> String {
+    if let AstNodeValue::DottedTail = node.value {
+        return format!(". {}", source_form(&node.children()[0]));
+    }
+    match quote(node) {
+        Ok(expr) => expr.to_string(),
+        Err(_) => node.value.to_string(),
+    }
+}
+
+fn eval_quote(node: &AstNode) -> Result<Value, RuntimeError> {
+    let children = node.children();
+    if children.len() != 2 {
+        return Err(RuntimeError::syntax(format!(
+            "quote expects exactly one argument, got {}",
+            children.len() - 1
+        )));
+    }
+    quote(&children[1])
+}
+
+fn eval_all(nodes: &[AstNode], env: &mut Environment) -> Result<Vec<Value>, RuntimeError> {
+    let mut values = Vec::new();
+    for node in nodes {
+        values.push(eval_tree(node, env)?);
+    }
+    Ok(values)
+}
+
+fn eval_args(node: &AstNode, env: &mut Environment) -> Result<Vec<Value>, RuntimeError> {
+    eval_all(&node.children()[1..], env)
+}
 
-    match identifier.as_str() {
-        "defvar" => {}
-        _ => unimplemented!(),
+/// Calls `function` with already evaluated `args`, also used by builtins like map that take
+/// functions as arguments
+pub(crate) fn apply(function: &Value, args: Vec<Value>) -> Result<Value, RuntimeError> {
+    match function {
+        Value::Closure(closure) => apply_closure(closure, args),
+        Value::Builtin(builtin) => builtin.call(&args),
+        _ => Err(not_callable(function)),
     }
-    Ok(new_node)
+}
+
+fn not_callable(value: &Value) -> RuntimeError {
+    RuntimeError::type_mismatch(format!("{} is not callable", value))
+        .with_note("only functions and builtins can be applied to arguments")
+}
+
+fn eval_expression(node: &AstNode, env: &mut Environment) -> Result<Value, RuntimeError> {
+    let Some(head) = node.children().first() else {
+        return Ok(Value::Nil);
+    };
+
+    if let AstNodeValue::Identifier(name) = &head.value {
+        match name.as_str() {
+            "quote" => return eval_quote(node),
+            "define" | "defvar" => return eval_define(node, env),
+            "set!" => return eval_set(node, env),
+            "lambda" => return eval_lambda(node, env),
+            "defun" => return eval_defun(node, env),
+            "begin" | "progn" => return eval_body(&node.children()[1..], env),
+            "let" => return eval_let(node, env, LetKind::Let),
+            "let*" => return eval_let(node, env, LetKind::LetStar),
+            "letrec" => return eval_let(node, env, LetKind::LetRec),
+            "if" => return eval_if(node, env),
+            "cond" => return eval_cond(node, env),
+            "when" => return eval_when(node, env, true),
+            "unless" => return eval_when(node, env, false),
+            "and" => return eval_and(node, env),
+            "or" => return eval_or(node, env),
+            "not" => return eval_not(node, env),
+            "try" => return eval_try(node, env),
+            "unwind-protect" => return eval_unwind_protect(node, env),
+            _ => {}
+        }
+    }
+
+    let head_value = eval_tree(head, env)?;
+    match &head_value {
+        Value::Closure(closure) => return apply_closure(closure, eval_args(node, env)?),
+        Value::Builtin(builtin) => return builtin.call(&eval_args(node, env)?),
+        _ => {}
+    }
+
+    // A parenthesized single value evaluates to itself, e.g. (1) or (x)
+    if node.children().len() == 1 {
+        return Ok(head_value);
+    }
+
+    Err(not_callable(&head_value))
+}
+
+/// Default limit on how deeply function calls may nest. The evaluator moves to a new stack
+/// segment on the heap when the native stack runs low, so the limit only stops runaway
+/// recursion and does not depend on the stack size of the calling thread.
+pub const DEFAULT_RECURSION_LIMIT: usize = 10_000;
+
+/// Stack space that has to be left before evaluating a node, well above what one level of
+/// evaluation takes in debug builds. Printing and comparing nested lists use it too.
+pub(crate) const STACK_RED_ZONE: usize = 256 * 1024;
+pub(crate) const STACK_SEGMENT_SIZE: usize = 4 * 1024 * 1024;
+
+thread_local! {
+    static CALL_DEPTH: Cell<usize> = const { Cell::new(0) };
+    static RECURSION_LIMIT: Cell<usize> = const { Cell::new(DEFAULT_RECURSION_LIMIT) };
+}
+
+/// Sets how deeply function calls may nest on the current thread before evaluation fails
+/// with a recursion error.
+pub fn set_recursion_limit(limit: usize) {
+    RECURSION_LIMIT.with(|l| l.set(limit));
 }
 
 fn eval_tree(node: &AstNode, env: &mut Environment) -> Result<Value, RuntimeError> {
-    if let AstNodeValue::Identifier(v) = &node.value {
-        let mut n = eval_identifier(node, v, env)?;
-        return eval_tree(&n, env);
-    } else {
-        match &node.value {
-            AstNodeValue::Operator(op) => eval_operator(node, op, env),
-            AstNodeValue::Literal(v) => Ok(v.clone()),
+    let result = stacker::maybe_grow(STACK_RED_ZONE, STACK_SEGMENT_SIZE, || {
+        eval_traced(node, env)
+    });
 
-            _ => {
-                unimplemented!("Not implemented\n{}", node);
-            }
+    // The innermost node that fails determines where the error is reported
+    result.map_err(|err| match (&err.span, &node.span) {
+        (None, Some(span)) => err.with_span(span.clone()),
+        _ => err,
+    })
+}
+
+fn eval_traced(node: &AstNode, env: &mut Environment) -> Result<Value, RuntimeError> {
+    if trace::is_enabled() {
+        // Quoting gives back the source form of the node, which is far more readable than the AST dump
+        match quote(node) {
+            Ok(expr) => trace::enter(&expr),
+            Err(_) => trace::enter(&node.value),
         }
+        let result = eval_node(node, env);
+        trace::exit(&result);
+        result
+    } else {
+        eval_node(node, env)
     }
 }
 
-pub fn eval_with_env(input: &str, env: &mut Environment) -> Result<Value, EvalError> {
+fn eval_node(node: &AstNode, env: &mut Environment) -> Result<Value, RuntimeError> {
+    match &node.value {
+        AstNodeValue::Operator(op) => eval_operator(node, op, env),
+        AstNodeValue::Literal(v) => Ok(v.clone()),
+        AstNodeValue::Identifier(v) => eval_identifier(v, env),
+        AstNodeValue::Expression => eval_expression(node, env),
+        AstNodeValue::Quote => quote(&node.children()[0]),
+        AstNodeValue::DottedTail => Err(dotted_outside_quote()),
+        AstNodeValue::Root => Err(RuntimeError::syntax(
+            "Cannot evaluate a program root as an expression".to_string(),
+        )),
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct EvalOptions {
+    /// File name reported in the spans of errors, e.g. the path of a script
+    pub source_name: Option<Rc<str>>,
+    /// Print the token stream to stderr before parsing
+    pub trace_tokens: bool,
+    /// Print the parsed AST to stderr before evaluating
+    pub trace_ast: bool,
+    /// Print every evaluation step and its result to stderr, indented by nesting depth
+    pub trace_eval: bool,
+    /// Turn whole float results into integers, e.g. (+ 2.5 2.5) gives 5 instead of 5.0.
+    /// Only meant for scripts relying on the behavior of earlier versions.
+    pub collapse_floats: bool,
+}
+
+impl EvalOptions {
+    pub fn trace_all() -> Self {
+        let mut options = EvalOptions::default();
+        options.set_trace(true);
+        options
+    }
+
+    pub fn set_trace(&mut self, enabled: bool) {
+        self.trace_tokens = enabled;
+        self.trace_ast = enabled;
+        self.trace_eval = enabled;
+    }
+}
+
+pub fn eval_with_options(
+    input: &str,
+    env: &mut Environment,
+    options: &EvalOptions,
+) -> Result<Value, EvalError> {
     let tokens = match tokenize(input) {
         Ok(tokens) => tokens,
         Err(e) => {
             return Err(EvalError::Tokenizing(e));
         }
     };
-    // println!("\nTokenize result:\n{:?}", tokens);
+    if options.trace_tokens {
+        eprintln!("Tokens:");
+        for token in &tokens {
+            eprintln!("  {:?}", token);
+        }
+    }
 
-    let root = match parse(tokens) {
+    let root = match parse(tokens, options.source_name.clone()) {
         Ok(root) => root,
         Err(e) => {
             return Err(EvalError::Parsing(e));
         }
     };
-    println!("\nParse result:\n{}", root);
-    let result = match eval_tree(&mut root.children().get(0).unwrap(), env) {
-        Ok(v) => v,
-        Err(e) => return Err(EvalError::Runtime(e)),
-    };
-    println!("\nParse result:\n{}", root);
-    Ok(result)
+    if options.trace_ast {
+        eprintln!("AST:\n{}", root);
+    }
+
+    trace::set_enabled(options.trace_eval);
+    value::set_collapse_floats(options.collapse_floats);
+    let result = eval_body(root.children(), env);
+    trace::set_enabled(false);
+    value::set_collapse_floats(false);
+
+    match result {
+        Ok(v) => Ok(v),
+        Err(e) => Err(EvalError::Runtime(e)),
+    }
 }
 
+pub fn eval_with_env(input: &str, env: &mut Environment) -> Result<Value, EvalError> {
+    eval_with_options(input, env, &EvalOptions::default())
+}
+
+/// Evaluates `input` in a fresh global environment. Function calls may nest up to the
+/// recursion limit of the current thread, see [`set_recursion_limit`].
 pub fn eval(input: &str) -> Result<Value, EvalError> {
-    let mut env = Environment::new();
-    env.set_var(&"pi".to_string(), Value::Float(3.14));
+    let mut env = Environment::global();
     eval_with_env(input, &mut env)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::token::TokenContent;
 
     #[test]
     fn test_operators() {
@@ -249,19 +795,21 @@ mod tests {
         assert_eq!(res, Value::Int(-1));
 
         let res = eval("(+ 2.5 2.5)").unwrap();
-        assert_eq!(res, Value::Int(5));
+        assert_eq!(res, Value::Float(5.0));
         let res = eval("(+ 2.5 2.6)").unwrap();
         assert_eq!(res, Value::Float(5.1));
 
         let res = eval("(/ 4 2)").unwrap();
         assert_eq!(res, Value::Int(2));
         let res = eval("(/ 5 2)").unwrap();
-        assert_eq!(res, Value::Float(2.5));
+        assert_eq!(res.to_string(), "5/2");
         let res = eval("(/ 0 2)").unwrap();
         assert_eq!(res, Value::Int(0));
         let res = eval("(/ 5 -2)").unwrap();
-        assert_eq!(res, Value::Float(-2.5));
+        assert_eq!(res.to_string(), "-5/2");
         let res = eval("(/ -5 -2)").unwrap();
+        assert_eq!(res.to_string(), "5/2");
+        let res = eval("(/ 5.0 2)").unwrap();
         assert_eq!(res, Value::Float(2.5));
         let res = eval("(// 5 2)").unwrap();
         assert_eq!(res, Value::Int(2));
@@ -274,16 +822,16 @@ mod tests {
         let res = eval("(* 2 3)").unwrap();
         assert_eq!(res, Value::Int(6));
         let res = eval("(* 2 2.5)").unwrap();
-        assert_eq!(res, Value::Int(5));
+        assert_eq!(res, Value::Float(5.0));
         let res = eval("(* 2 2.1)").unwrap();
         assert_eq!(res, Value::Float(4.2));
 
         let res = eval("(^ 2 3)").unwrap();
         assert_eq!(res, Value::Int(8));
         let res = eval("(^ 2 -1)").unwrap();
-        assert_eq!(res, Value::Float(0.5));
+        assert_eq!(res.to_string(), "1/2");
         let res = eval("(^ 16 (/ 1 2))").unwrap();
-        assert_eq!(res, Value::Int(4));
+        assert_eq!(res, Value::Float(4.0));
 
         let res = eval("(+ (* 2 (- 10 3)) (/ (+ 15 5) (- 8 4)))").unwrap();
         assert_eq!(res, Value::Int(19));
@@ -319,4 +867,1359 @@ mod tests {
         let res = eval("(>= 1 0 0 -5)").unwrap();
         assert_eq!(res, Value::Boolean(true));
     }
+
+    #[test]
+    fn test_define() {
+        let mut env = Environment::new();
+        let res = eval_with_env("(define x 10)", &mut env).unwrap();
+        assert_eq!(res, Value::Int(10));
+        let res = eval_with_env("(+ x 1)", &mut env).unwrap();
+        assert_eq!(res, Value::Int(11));
+
+        let res = eval_with_env("(defvar y (* x 2))", &mut env).unwrap();
+        assert_eq!(res, Value::Int(20));
+        let res = eval_with_env("(y)", &mut env).unwrap();
+        assert_eq!(res, Value::Int(20));
+
+        let res = eval_with_env("(define x \"redefined\")", &mut env).unwrap();
+        assert_eq!(res, Value::String("redefined".into()));
+        let res = eval_with_env("x", &mut env).unwrap();
+        assert_eq!(res, Value::String("redefined".into()));
+
+        assert!(eval_with_env("(+ z 1)", &mut env).is_err());
+        assert!(eval_with_env("(define 1 2)", &mut env).is_err());
+        assert!(eval_with_env("(define x)", &mut env).is_err());
+
+        // Malformed forms are reported the way they were written
+        let message = |input: &str| eval(input).unwrap_err().message().to_string();
+        assert_eq!(
+            message("(define (f x) 1)"),
+            "define expects an identifier as its name, found (f x)"
+        );
+        assert_eq!(
+            message("(lambda (x \"y\") 1)"),
+            "Parameter names must be identifiers, found \"y\""
+        );
+        assert_eq!(
+            message("(let (loop) 1)"),
+            "Bindings must be of the form (name value), found loop"
+        );
+        assert_eq!(
+            message("(let ((1.5 2)) 1)"),
+            "Binding names must be identifiers, found 1.5"
+        );
+        assert_eq!(
+            message("(cond 1)"),
+            "cond clauses must be of the form (test body...), found 1"
+        );
+    }
+
+    #[test]
+    fn test_functions() {
+        let mut env = Environment::new();
+        let res = eval_with_env("((lambda (x y) (+ x y)) 1 2)", &mut env).unwrap();
+        assert_eq!(res, Value::Int(3));
+        let res = eval_with_env("((lambda () 42))", &mut env).unwrap();
+        assert_eq!(res, Value::Int(42));
+
+        eval_with_env("(defun square (x) (* x x))", &mut env).unwrap();
+        let res = eval_with_env("(square 7)", &mut env).unwrap();
+        assert_eq!(res, Value::Int(49));
+        let res = eval_with_env("(square (square 2))", &mut env).unwrap();
+        assert_eq!(res, Value::Int(16));
+
+        eval_with_env("(define add (lambda (a b) (+ a b)))", &mut env).unwrap();
+        let res = eval_with_env("(add (square 3) 1)", &mut env).unwrap();
+        assert_eq!(res, Value::Int(10));
+
+        eval_with_env("(define n 100)", &mut env).unwrap();
+        eval_with_env("(defun shadow (n) (* n 2))", &mut env).unwrap();
+        let res = eval_with_env("(shadow 4)", &mut env).unwrap();
+        assert_eq!(res, Value::Int(8));
+        let res = eval_with_env("n", &mut env).unwrap();
+        assert_eq!(res, Value::Int(100));
+
+        eval_with_env(
+            "(define make-adder (lambda (n) (lambda (x) (+ x n))))",
+            &mut env,
+        )
+        .unwrap();
+        let res = eval_with_env("((make-adder 5) 10)", &mut env).unwrap();
+        assert_eq!(res, Value::Int(15));
+
+        assert!(eval_with_env("(square 1 2)", &mut env).is_err());
+        assert!(eval_with_env("(lambda (1) 1)", &mut env).is_err());
+        assert!(eval_with_env("(lambda (x))", &mut env).is_err());
+        assert!(eval_with_env("(n 1)", &mut env).is_err());
+    }
+
+    #[test]
+    fn test_scopes() {
+        let mut env = Environment::global();
+        eval_with_env("(define x 1)", &mut env).unwrap();
+        eval_with_env(
+            "(defun shadow (x) (define y x) (set! x (* x 10)) x)",
+            &mut env,
+        )
+        .unwrap();
+        let res = eval_with_env("(shadow 5)", &mut env).unwrap();
+        assert_eq!(res, Value::Int(50));
+        let res = eval_with_env("x", &mut env).unwrap();
+        assert_eq!(res, Value::Int(1));
+        assert!(eval_with_env("y", &mut env).is_err());
+
+        eval_with_env("(defun bump () (set! x (+ x 1)))", &mut env).unwrap();
+        eval_with_env("(bump)", &mut env).unwrap();
+        let res = eval_with_env("(bump)", &mut env).unwrap();
+        assert_eq!(res, Value::Int(3));
+        let res = eval_with_env("x", &mut env).unwrap();
+        assert_eq!(res, Value::Int(3));
+
+        eval_with_env(
+            "(defun make-counter () (define count 0) (lambda () (set! count (+ count 1))))",
+            &mut env,
+        )
+        .unwrap();
+        eval_with_env("(define counter (make-counter))", &mut env).unwrap();
+        eval_with_env("(counter)", &mut env).unwrap();
+        let res = eval_with_env("(counter)", &mut env).unwrap();
+        assert_eq!(res, Value::Int(2));
+        let res = eval_with_env("((make-counter))", &mut env).unwrap();
+        assert_eq!(res, Value::Int(1));
+
+        // Globals defined after a function are still visible to it
+        eval_with_env("(defun first () (second))", &mut env).unwrap();
+        eval_with_env("(defun second () 2)", &mut env).unwrap();
+        let res = eval_with_env("(first)", &mut env).unwrap();
+        assert_eq!(res, Value::Int(2));
+
+        assert!(eval_with_env("(set! undefined 1)", &mut env).is_err());
+    }
+
+    #[test]
+    fn test_conditionals() {
+        let mut env = Environment::global();
+
+        // Only false and null are falsy
+        assert_eq!(eval("(if true 1 2)").unwrap(), Value::Int(1));
+        assert_eq!(eval("(if false 1 2)").unwrap(), Value::Int(2));
+        assert_eq!(eval("(if () 1 2)").unwrap(), Value::Int(1));
+        assert_eq!(eval("(if 0 1 2)").unwrap(), Value::Int(1));
+        assert_eq!(eval("(if \"\" 1 2)").unwrap(), Value::Int(1));
+        assert_eq!(eval("(if (< 2 1) 1)").unwrap(), Value::None);
+        assert_eq!(eval("(if null 1 2)").unwrap(), Value::Int(2));
+        assert_eq!(eval("(= (if false 1) null)").unwrap(), Value::Boolean(true));
+
+        assert_eq!(eval("(not false)").unwrap(), Value::Boolean(true));
+        assert_eq!(eval("(not 0)").unwrap(), Value::Boolean(false));
+        assert_eq!(eval("(not ())").unwrap(), Value::Boolean(false));
+        assert_eq!(eval("(not null)").unwrap(), Value::Boolean(true));
+
+        assert_eq!(eval("(and)").unwrap(), Value::Boolean(true));
+        assert_eq!(eval("(and 1 2 3)").unwrap(), Value::Int(3));
+        assert_eq!(eval("(and 1 false 3)").unwrap(), Value::Boolean(false));
+        assert_eq!(eval("(or)").unwrap(), Value::Boolean(false));
+        assert_eq!(eval("(or false 2 3)").unwrap(), Value::Int(2));
+        assert_eq!(eval("(or false ())").unwrap(), Value::Nil);
+
+        assert_eq!(eval("(when (= 1 1) 1 2)").unwrap(), Value::Int(2));
+        assert_eq!(eval("(when (= 1 2) 1 2)").unwrap(), Value::None);
+        assert_eq!(eval("(unless (= 1 2) 1 2)").unwrap(), Value::Int(2));
+        assert_eq!(eval("(unless (= 1 1) 1 2)").unwrap(), Value::None);
+
+        eval_with_env(
+            "(defun sign (x) (cond ((< x 0) -1) ((= x 0) 0) (else 1)))",
+            &mut env,
+        )
+        .unwrap();
+        assert_eq!(
+            eval_with_env("(sign -5)", &mut env).unwrap(),
+            Value::Int(-1)
+        );
+        assert_eq!(eval_with_env("(sign 0)", &mut env).unwrap(), Value::Int(0));
+        assert_eq!(eval_with_env("(sign 5)", &mut env).unwrap(), Value::Int(1));
+        assert_eq!(eval("(cond (false 1) (7))").unwrap(), Value::Int(7));
+        assert_eq!(eval("(cond (false 1))").unwrap(), Value::None);
+
+        eval_with_env(
+            "(defun fact (n) (if (<= n 1) 1 (* n (fact (- n 1)))))",
+            &mut env,
+        )
+        .unwrap();
+        assert_eq!(
+            eval_with_env("(fact 10)", &mut env).unwrap(),
+            Value::Int(3628800)
+        );
+
+        // Branches that are not taken are never evaluated
+        eval_with_env("(define hits 0)", &mut env).unwrap();
+        eval_with_env("(if true 1 (set! hits 1))", &mut env).unwrap();
+        eval_with_env("(and false (set! hits 2))", &mut env).unwrap();
+        eval_with_env("(or true (set! hits 3))", &mut env).unwrap();
+        eval_with_env("(cond (true 1) (else (set! hits 4)))", &mut env).unwrap();
+        eval_with_env("(when false (set! hits 5))", &mut env).unwrap();
+        assert_eq!(eval_with_env("hits", &mut env).unwrap(), Value::Int(0));
+
+        assert!(eval("(if)").is_err());
+        assert!(eval("(if true)").is_err());
+        assert!(eval("(not 1 2)").is_err());
+        assert!(eval("(cond 1)").is_err());
+    }
+
+    #[test]
+    fn test_let() {
+        let mut env = Environment::global();
+        eval_with_env("(define x 1)", &mut env).unwrap();
+
+        let res = eval_with_env("(let ((x 2) (y x)) (+ x y))", &mut env).unwrap();
+        assert_eq!(res, Value::Int(3));
+        let res = eval_with_env("(let* ((x 2) (y x)) (+ x y))", &mut env).unwrap();
+        assert_eq!(res, Value::Int(4));
+        let res = eval_with_env("(let () 5)", &mut env).unwrap();
+        assert_eq!(res, Value::Int(5));
+        let res =
+            eval_with_env("(let ((x 10)) (define z 1) (set! x (+ x z)) x)", &mut env).unwrap();
+        assert_eq!(res, Value::Int(11));
+
+        // Locals are discarded afterward and never clobber globals
+        assert_eq!(eval_with_env("x", &mut env).unwrap(), Value::Int(1));
+        assert!(eval_with_env("y", &mut env).is_err());
+        assert!(eval_with_env("z", &mut env).is_err());
+
+        let res = eval_with_env(
+            "(letrec ((even (lambda (n) (if (= n 0) true (odd (- n 1))))) \
+                      (odd (lambda (n) (if (= n 0) false (even (- n 1)))))) \
+               (even 10))",
+            &mut env,
+        )
+        .unwrap();
+        assert_eq!(res, Value::Boolean(true));
+
+        let res = eval_with_env(
+            "(defun make-acc (start) (let ((total start)) (lambda (n) (set! total (+ total n)))))",
+            &mut env,
+        );
+        assert!(res.is_ok());
+        eval_with_env("(define acc (make-acc 10))", &mut env).unwrap();
+        eval_with_env("(acc 5)", &mut env).unwrap();
+        assert_eq!(eval_with_env("(acc 5)", &mut env).unwrap(), Value::Int(20));
+
+        assert!(eval_with_env("(let ((x)) x)", &mut env).is_err());
+        assert!(eval_with_env("(let ((1 2)) 1)", &mut env).is_err());
+        assert!(eval_with_env("(let ((x 1)))", &mut env).is_err());
+        assert!(eval_with_env("(let x 1)", &mut env).is_err());
+    }
+
+    #[test]
+    fn test_lists() {
+        let mut env = Environment::global();
+        let list = |items: &[i64]| Value::list(items.iter().map(|i| Value::Int(*i)));
+
+        assert_eq!(eval("(list 1 2 3)").unwrap(), list(&[1, 2, 3]));
+        assert_eq!(eval("(list)").unwrap(), list(&[]));
+        assert_eq!(eval("(cons 1 (list 2 3))").unwrap(), list(&[1, 2, 3]));
+        assert_eq!(eval("(cons 1 (list))").unwrap(), list(&[1]));
+        assert_eq!(eval("(car (list 1 2 3))").unwrap(), Value::Int(1));
+        assert_eq!(eval("(cdr (list 1 2 3))").unwrap(), list(&[2, 3]));
+        assert_eq!(eval("(cdr (list 1))").unwrap(), list(&[]));
+        assert_eq!(eval("(length (list 1 2 3))").unwrap(), Value::Int(3));
+        assert_eq!(
+            eval("(append (list 1) (list) (list 2 3))").unwrap(),
+            list(&[1, 2, 3])
+        );
+        assert_eq!(eval("(append)").unwrap(), list(&[]));
+        assert_eq!(eval("(reverse (list 1 2 3))").unwrap(), list(&[3, 2, 1]));
+
+        assert_eq!(eval("(null? (list))").unwrap(), Value::Boolean(true));
+        assert_eq!(eval("(null? (list 1))").unwrap(), Value::Boolean(false));
+        assert_eq!(eval("(null? 0)").unwrap(), Value::Boolean(false));
+        assert_eq!(eval("(null? ())").unwrap(), Value::Boolean(true));
+        assert_eq!(eval("(cons 1 ())").unwrap().to_string(), "(1)");
+        assert_eq!(eval("(pair? (list 1))").unwrap(), Value::Boolean(true));
+        assert_eq!(eval("(pair? (list))").unwrap(), Value::Boolean(false));
+        assert_eq!(eval("(pair? 1)").unwrap(), Value::Boolean(false));
+
+        assert_eq!(
+            eval("(= (list 1 (list 2)) (list 1 (list 2)))").unwrap(),
+            Value::Boolean(true)
+        );
+        assert_eq!(
+            eval("(= (list 1 2) (list 1 3))").unwrap(),
+            Value::Boolean(false)
+        );
+
+        assert_eq!(
+            eval("(list 1 \"a\" (list 2.5 true))").unwrap().to_string(),
+            "(1 \"a\" (2.5 true))"
+        );
+        assert_eq!(eval("(list)").unwrap().to_string(), "()");
+
+        eval_with_env(
+            "(defun sum (xs) (if (null? xs) 0 (+ (car xs) (sum (cdr xs)))))",
+            &mut env,
+        )
+        .unwrap();
+        assert_eq!(
+            eval_with_env("(sum (list 1 2 3 4))", &mut env).unwrap(),
+            Value::Int(10)
+        );
+
+        // User definitions can shadow builtins
+        eval_with_env("(defun length (xs) 42)", &mut env).unwrap();
+        assert_eq!(
+            eval_with_env("(length (list))", &mut env).unwrap(),
+            Value::Int(42)
+        );
+
+        // Pairs can end in any value, and tails are shared instead of copied
+        assert_eq!(eval("(cons 1 2)").unwrap().to_string(), "(1 . 2)");
+        assert_eq!(
+            eval("(cons 1 (cons 2 3))").unwrap().to_string(),
+            "(1 2 . 3)"
+        );
+        assert_eq!(eval("(cdr (cons 1 2))").unwrap(), Value::Int(2));
+        assert_eq!(eval("(pair? (cons 1 2))").unwrap(), Value::Boolean(true));
+        assert_eq!(
+            eval("(let ((xs (list 1 2))) (eq? (cdr (cons 0 xs)) xs))").unwrap(),
+            Value::Boolean(true)
+        );
+        assert_eq!(
+            eval("(eq? (list 1) (list 1))").unwrap(),
+            Value::Boolean(false)
+        );
+        assert!(eval("(length (cons 1 2))").is_err());
+
+        let mut program = String::from("(let ((xs (list)))");
+        for i in 0..20_000 {
+            program.push_str(&format!("(set! xs (cons {} xs))", i));
+        }
+        program.push_str("(list (length xs) (car xs) (car (cdr xs))))");
+        assert_eq!(eval(&program).unwrap().to_string(), "(20000 19999 19998)");
+
+        // Lists nested through their first element are printed, compared and dropped without
+        // running out of stack
+        set_recursion_limit(100_000);
+        let nest = "(defun nest (n acc) (if (= n 0) acc (nest (- n 1) (list acc))))";
+        let printed = eval(&format!("{} (nest 50000 1)", nest))
+            .unwrap()
+            .to_string();
+        assert_eq!(
+            printed,
+            format!("{}1{}", "(".repeat(50000), ")".repeat(50000))
+        );
+        assert_eq!(
+            eval(&format!("{} (= (nest 90000 1) (nest 90000 1))", nest)).unwrap(),
+            Value::Boolean(true)
+        );
+        set_recursion_limit(DEFAULT_RECURSION_LIMIT);
+
+        assert!(eval("(car (list))").is_err());
+        assert!(eval("(cdr 1)").is_err());
+        assert!(eval("(cons 1)").is_err());
+        assert!(eval("(append (list 1) 2)").is_err());
+    }
+
+    #[test]
+    fn test_quote() {
+        let mut env = Environment::global();
+        let sym = |name: &str| Value::Symbol(Symbol::intern(name));
+
+        assert_eq!(
+            eval("'(1 2 3)").unwrap(),
+            Value::list(vec![Value::Int(1), Value::Int(2), Value::Int(3)])
+        );
+        assert_eq!(eval("'()").unwrap(), Value::Nil);
+        assert_eq!(eval("'foo").unwrap(), sym("foo"));
+        assert_eq!(eval("'42").unwrap(), Value::Int(42));
+        assert_eq!(eval("'\"s\"").unwrap(), Value::String("s".into()));
+        assert_eq!(eval("(quote foo)").unwrap(), sym("foo"));
+        assert_eq!(
+            eval("(quote (a 1))").unwrap(),
+            Value::list(vec![sym("a"), Value::Int(1)])
+        );
+
+        // Quoted code is data and is never evaluated
+        assert_eq!(
+            eval("(car '(undefined-fn 1))").unwrap(),
+            sym("undefined-fn")
+        );
+        assert_eq!(eval("(length '(+ 1 (* 2 3)))").unwrap(), Value::Int(3));
+        assert_eq!(eval("'(+ 1 (* 2 3))").unwrap().to_string(), "(+ 1 (* 2 3))");
+        assert_eq!(
+            eval("'(a (b c) \"d\" 1.5)").unwrap().to_string(),
+            "(a (b c) \"d\" 1.5)"
+        );
+        assert_eq!(eval("''a").unwrap().to_string(), "(quote a)");
+        assert_eq!(eval("'+").unwrap(), sym("+"));
+
+        // Dotted pairs read back the way they are printed
+        assert_eq!(eval("'(1 . 2)").unwrap(), eval("(cons 1 2)").unwrap());
+        for input in [
+            "(1 . 2)",
+            "(a b . c)",
+            "(+ . 1)",
+            "((1 . 2) . 3)",
+            "(1 . |.|)",
+        ] {
+            let printed = eval(&format!("'{}", input)).unwrap().to_string();
+            assert_eq!(printed, input);
+        }
+        assert_eq!(eval("'(1 . (2 3))").unwrap().to_string(), "(1 2 3)");
+        assert_eq!(eval("(cdr '(a . b))").unwrap(), sym("b"));
+        assert_eq!(eval("'(1 #;x . #;y 2)").unwrap().to_string(), "(1 . 2)");
+        for (input, message) in [
+            (
+                "'(1 . 2 3)",
+                "Expected ')' after the expression following '.'",
+            ),
+            ("'(. 2)", "Expected an expression before '.'"),
+            ("'(1 .)", "Expected an expression after '.'"),
+            ("'(1 . . 2)", "Expected an expression after '.'"),
+            (
+                "'.",
+                "Unexpected '.', only a list can have a dotted tail like (1 . 2)",
+            ),
+            ("(+ 1 . 2)", "Cannot evaluate a dotted list"),
+        ] {
+            assert_eq!(eval(input).unwrap_err().message(), message, "{}", input);
+        }
+
+        eval_with_env("(define xs '(1 2 3))", &mut env).unwrap();
+        assert_eq!(
+            eval_with_env("(car (cdr xs))", &mut env).unwrap(),
+            Value::Int(2)
+        );
+        assert_eq!(
+            eval_with_env("(= 'a 'a)", &mut env).unwrap(),
+            Value::Boolean(true)
+        );
+        assert_eq!(
+            eval_with_env("(= 'a 'b)", &mut env).unwrap(),
+            Value::Boolean(false)
+        );
+
+        assert!(eval("'").is_err());
+        assert!(eval("(')").is_err());
+        assert!(eval("(quote)").is_err());
+        assert!(eval("(quote a b)").is_err());
+    }
+
+    #[test]
+    fn test_symbols() {
+        let mut env = Environment::global();
+        assert_eq!(Symbol::intern("abc"), Symbol::intern("abc"));
+        assert_ne!(Symbol::intern("abc"), Symbol::intern("abd"));
+        assert_eq!(Symbol::intern("abc").name(), "abc");
+
+        assert_eq!(eval("(eq? 'a 'a)").unwrap(), Value::Boolean(true));
+        assert_eq!(eval("(eq? 'a 'b)").unwrap(), Value::Boolean(false));
+        assert_eq!(eval("(eq? 'a \"a\")").unwrap(), Value::Boolean(false));
+        assert_eq!(eval("(eq? 1 1)").unwrap(), Value::Boolean(true));
+        assert_eq!(eval("(eq? '() '())").unwrap(), Value::Boolean(true));
+        // Strings are the same object when they come from the same binding
+        assert_eq!(
+            eval("(let ((s \"a\")) (eq? s s))").unwrap(),
+            Value::Boolean(true)
+        );
+        assert_eq!(
+            eval("(let ((s \"a\")) (eq? (car (list s)) s))").unwrap(),
+            Value::Boolean(true)
+        );
+        assert_eq!(eval("(eq? \"a\" \"a\")").unwrap(), Value::Boolean(false));
+        assert_eq!(
+            eval("(let ((s \"a\")) (eq? (+ s \"\") s))").unwrap(),
+            Value::Boolean(false)
+        );
+        assert_eq!(eval("(symbol? 'a)").unwrap(), Value::Boolean(true));
+        assert_eq!(eval("(symbol? \"a\")").unwrap(), Value::Boolean(false));
+
+        assert_eq!(
+            eval("(symbol->string 'hello)").unwrap(),
+            Value::String("hello".into())
+        );
+        assert_eq!(
+            eval("(string->symbol \"hello\")").unwrap(),
+            Value::Symbol(Symbol::intern("hello"))
+        );
+        assert_eq!(
+            eval("(eq? (string->symbol \"x\") 'x)").unwrap(),
+            Value::Boolean(true)
+        );
+
+        // Symbolic data can drive dispatch
+        eval_with_env(
+            "(defun run (instr a b) (cond ((eq? (car instr) 'add) (+ a b)) ((eq? (car instr) 'mul) (* a b))))",
+            &mut env,
+        )
+        .unwrap();
+        assert_eq!(
+            eval_with_env("(run '(add) 2 3)", &mut env).unwrap(),
+            Value::Int(5)
+        );
+        assert_eq!(
+            eval_with_env("(run '(mul) 2 3)", &mut env).unwrap(),
+            Value::Int(6)
+        );
+        assert_eq!(eval("(car '(op add))").unwrap().to_string(), "op");
+
+        assert!(eval("(symbol->string \"a\")").is_err());
+        assert!(eval("(string->symbol 'a)").is_err());
+        assert!(eval("(eq? 'a)").is_err());
+    }
+
+    #[test]
+    fn test_builtins() {
+        let mut env = Environment::global();
+        assert_eq!(eval("(abs -5)").unwrap(), Value::Int(5));
+        assert_eq!(eval("(abs -2.5)").unwrap(), Value::Float(2.5));
+        assert_eq!(eval("(min 3 1 2)").unwrap(), Value::Int(1));
+        assert_eq!(eval("(max 3 1 2.5)").unwrap(), Value::Int(3));
+        assert_eq!(eval("(max 7)").unwrap(), Value::Int(7));
+        assert_eq!(eval("(sqrt 16)").unwrap(), Value::Float(4.0));
+
+        // Builtins are ordinary values bound in the global environment
+        assert_eq!(eval("car").unwrap().to_string(), "<builtin car>");
+        assert_eq!(eval("(eq? car car)").unwrap(), Value::Boolean(true));
+        eval_with_env("(define first car)", &mut env).unwrap();
+        assert_eq!(
+            eval_with_env("(first '(1 2))", &mut env).unwrap(),
+            Value::Int(1)
+        );
+        eval_with_env("(defun apply-to (f x) (f x))", &mut env).unwrap();
+        assert_eq!(
+            eval_with_env("(apply-to abs -3)", &mut env).unwrap(),
+            Value::Int(3)
+        );
+
+        let err = eval("(car)").unwrap_err();
+        assert_eq!(err.message(), "car expects 1 argument(s), got 0");
+        let err = eval("(min)").unwrap_err();
+        assert_eq!(err.message(), "min expects at least 1 argument(s), got 0");
+        assert!(eval("(cons 1 '() 3)").is_err());
+        assert!(eval("(abs 'a)").is_err());
+        assert!(eval("(min 1 \"a\")").is_err());
+        assert!(eval("(sqrt -1)").is_err());
+    }
+
+    #[test]
+    fn test_programs() {
+        assert_eq!(eval("").unwrap(), Value::None);
+        assert_eq!(eval("1 2 3").unwrap(), Value::Int(3));
+        assert_eq!(
+            eval("(define x 1) (define y 2) (+ x y)").unwrap(),
+            Value::Int(3)
+        );
+
+        let program = "
+            (defun square (x) (* x x))
+            (define total 0)
+            (set! total (+ total (square 3)))
+            (set! total (+ total (square 4)))
+            total
+        ";
+        assert_eq!(eval(program).unwrap(), Value::Int(25));
+
+        // Every form runs in order, so the error in the second one is reported
+        assert!(eval("(define x 1) (+ x undefined) (define y 2)").is_err());
+
+        assert_eq!(eval("(begin)").unwrap(), Value::None);
+        assert_eq!(eval("(begin 1 2 3)").unwrap(), Value::Int(3));
+        assert_eq!(
+            eval("(progn (define z 5) (* z 2))").unwrap(),
+            Value::Int(10)
+        );
+        assert_eq!(
+            eval("(define n 0) (if true (begin (set! n (+ n 1)) (set! n (+ n 1))) 0) n").unwrap(),
+            Value::Int(2)
+        );
+    }
+
+    #[test]
+    fn test_trace() {
+        let mut env = Environment::global();
+        let options = EvalOptions::trace_all();
+        let res = eval_with_options("(defun f (x) (* x 2)) (f (+ 1 2))", &mut env, &options);
+        assert_eq!(res.unwrap(), Value::Int(6));
+        assert!(!trace::is_enabled());
+
+        assert!(eval_with_options("(f undefined)", &mut env, &options).is_err());
+        assert!(!trace::is_enabled());
+    }
+
+    #[test]
+    fn test_operator_values() {
+        let mut env = Environment::global();
+        let program = "(defun map2 (f xs ys)
+                (if (null? xs) '() (cons (f (car xs) (car ys)) (map2 f (cdr xs) (cdr ys)))))
+            (defun fold (f acc xs) (if (null? xs) acc (fold f (f acc (car xs)) (cdr xs))))
+            (define add +)";
+        eval_with_env(program, &mut env).unwrap();
+        let mut eval = |input: &str| eval_with_env(input, &mut env).unwrap().to_string();
+        assert_eq!(eval("(map2 + '(1 2 3) '(10 20 30))"), "(11 22 33)");
+        assert_eq!(eval("(map2 < '(1 5) '(2 3))"), "(true false)");
+        assert_eq!(eval("(map2 // '(7 9) '(2 4))"), "(3 2)");
+        assert_eq!(eval("(add 1 2 3)"), "6");
+        assert_eq!(eval("(fold * 1 '(1 2 3 4))"), "24");
+        assert_eq!(eval("(fold ^ 2 '(3 2))"), "64");
+        assert_eq!(eval("((if true - +) 5)"), "-5");
+        assert_eq!(eval("(|+|)"), "0");
+        assert_eq!(eval("+"), "<builtin +>");
+
+        // apply and map call the operators like any other function
+        eval("(define nums '(1 2 3)) (define xs '(1 2 3)) (define ys '(10 20 30))");
+        assert_eq!(eval("(apply < nums)"), "true");
+        assert_eq!(eval("(apply > nums)"), "false");
+        assert_eq!(eval("(apply + 1 2 nums)"), "9");
+        assert_eq!(eval("(apply add '())"), "0");
+        assert_eq!(eval("(map + xs ys)"), "(11 22 33)");
+        assert_eq!(eval("(map - xs)"), "(-1 -2 -3)");
+        assert_eq!(eval("(map < xs '(2 2))"), "(true false)");
+        assert_eq!(eval("(map (lambda (x) (* x x)) xs)"), "(1 4 9)");
+        assert_eq!(eval("(apply map list '((1 2) (3 4)))"), "((1 3) (2 4))");
+        assert_eq!(eval("(map car '())"), "()");
+        for (input, message) in [
+            ("(apply + 1)", "apply expects a list, found 1"),
+            ("(apply 1 '())", "1 is not callable"),
+            ("(map + '(1) 1)", "map expects a list, found 1"),
+            ("(map car '(1))", "car expects a list, found 1"),
+            ("(map)", "map expects at least 2 argument(s), got 0"),
+        ] {
+            let err = super::eval(input).unwrap_err();
+            assert_eq!(err.message(), message, "{}", input);
+        }
+
+        // Called as values the operators behave like direct calls
+        for (call, direct) in [
+            ("(add)", "(+)"),
+            ("(add \"a\" \"b\")", "(+ \"a\" \"b\")"),
+            ("(|/| 1 3)", "(/ 1 3)"),
+            ("(|%| -7 2)", "(% -7 2)"),
+            ("(|=| 5 5.0 5)", "(= 5 5.0 5)"),
+            ("(|!=| 1 2 1)", "(!= 1 2 1)"),
+            ("(|>=| 3 3 1)", "(>= 3 3 1)"),
+        ] {
+            assert_eq!(eval(call), eval(direct), "{}", call);
+        }
+        let err = eval_with_env("(|-|)", &mut env).unwrap_err();
+        assert_eq!(err.message(), "- expects at least 1 argument(s), got 0");
+
+        // All arguments are evaluated before the operator is applied, like for any call
+        for input in ["(< 2 1 undefined)", "(|<| 2 1 undefined)"] {
+            let err = eval_with_env(input, &mut env).unwrap_err();
+            assert_eq!(err.message(), "Unbound variable 'undefined'", "{}", input);
+        }
+
+        // Rebinding an operator name takes it off the fast path
+        let mut env = Environment::global();
+        let mut eval = |input: &str| eval_with_env(input, &mut env).unwrap().to_string();
+        assert_eq!(eval("(let ((+ *)) (+ 2 3))"), "6");
+        assert_eq!(eval("(let* ((< >) (// <)) (// 1 2))"), "false");
+        assert_eq!(eval("((lambda (+ x) (+ x x)) - 3)"), "0");
+        assert_eq!(eval("(defun twice (* x) (* (* x)))"), "<function twice>");
+        assert_eq!(eval("(twice (lambda (n) (+ n 1)) 5)"), "7");
+        assert_eq!(eval("(+ 2 3)"), "5");
+        assert_eq!(eval("(define + -) (+ 5 3)"), "2");
+        assert_eq!(eval("(set! + *) (+ 5 3)"), "15");
+        assert_eq!(eval("(defun < (a b) (> a b)) (< 1 2)"), "false");
+        assert_eq!(eval("(set! < 0) (- 5 3)"), "2");
+        assert_eq!(
+            eval_with_env("(< 1 2)", &mut env).unwrap_err().message(),
+            "0 is not callable"
+        );
+    }
+
+    #[test]
+    fn test_symbol_syntax() {
+        let mut env = Environment::global();
+        let program = "(define *global* 1) (define my_var 2) (define <=> 3) (define -x 4)
+            (defun empty? (l) (= (length l) 0))
+            (set! my_var (+ my_var 10))
+            (list *global* my_var <=> -x (- -x) (empty? '()) (symbol->string 'string->symbol))";
+        assert_eq!(
+            eval_with_env(program, &mut env).unwrap().to_string(),
+            "(1 12 3 4 -4 true \"string->symbol\")"
+        );
+
+        // An r followed by '#' is only a raw string when the '#'s are followed by a quote
+        assert_eq!(eval("(define r#x 1) (+ r#x 1)").unwrap(), Value::Int(2));
+        assert_eq!(eval("'(r# r##a r)").unwrap().to_string(), "(r# r##a r)");
+
+        // Operators are symbols, only in head position they are applied natively
+        assert_eq!(eval("(- 5 -3)").unwrap(), Value::Int(8));
+        assert_eq!(eval("(<= 1 2)").unwrap(), Value::Boolean(true));
+        assert_eq!(eval("'(+ <= //)").unwrap().to_string(), "(+ <= //)");
+        assert_eq!(
+            eval("(eq? '+ (string->symbol \"+\"))").unwrap(),
+            Value::Boolean(true)
+        );
+
+        // |quoted symbols| can hold any character and print the same way
+        assert_eq!(
+            eval("'|hello world|").unwrap(),
+            Value::Symbol(Symbol::intern("hello world"))
+        );
+        assert_eq!(
+            eval_with_env("(define |my var| 5) |my var|", &mut env).unwrap(),
+            Value::Int(5)
+        );
+        assert_eq!(
+            eval(r"'(|a b| |x| |1| |a\|b| || |+|)").unwrap().to_string(),
+            r"(|a b| x |1| |a\|b| || +)"
+        );
+        assert_eq!(
+            eval("(symbol? (car '(|true| false)))").unwrap(),
+            Value::Boolean(true)
+        );
+        assert_eq!(
+            eval("(list '|true| (string->symbol \"false\") true '|null| null)")
+                .unwrap()
+                .to_string(),
+            "(|true| |false| true |null| null)"
+        );
+        assert_eq!(
+            eval("'|abc").unwrap_err().message(),
+            "Unclosed symbol, expected '|'"
+        );
+    }
+
+    #[test]
+    fn test_comments() {
+        let program = "; squares a number\n(defun sq (x) (* x x)) ; trailing\n(sq 4)";
+        assert_eq!(eval(program).unwrap(), Value::Int(16));
+        assert_eq!(eval("(+ 1 #| 2 #| nested |# |# 3)").unwrap(), Value::Int(4));
+        assert_eq!(
+            eval("#|\n(car 1)\n|# \"; not a comment\"")
+                .unwrap()
+                .to_string(),
+            "\"; not a comment\""
+        );
+        assert_eq!(eval("(+ 1 #;(car 1) 2)").unwrap(), Value::Int(3));
+        assert_eq!(eval("(list #; #; 1 2 3)").unwrap().to_string(), "(3)");
+        assert_eq!(eval("(#;ignored + 1 2)").unwrap(), Value::Int(3));
+        assert_eq!(eval("'#;a b").unwrap(), Value::Symbol(Symbol::intern("b")));
+        assert_eq!(eval("1 #;2").unwrap(), Value::Int(1));
+
+        // Tokens after a block comment keep their positions
+        let err = eval("#| a\nb |# (car 1)").unwrap_err();
+        let span = err.diagnostic(None).span.unwrap();
+        assert_eq!((span.line, span.column), (2, 6));
+
+        assert_eq!(
+            eval("(+ 1 #| 2").unwrap_err().message(),
+            "Unclosed block comment, expected '|#'"
+        );
+        assert_eq!(
+            eval("(+ 1 #;)").unwrap_err().message(),
+            "Expected an expression to comment out after '#;'"
+        );
+        assert!(eval("#;").is_err());
+    }
+
+    #[test]
+    fn test_source_positions() {
+        // Columns and lengths count chars, offsets count bytes, "\r\n" ends a line
+        let tokens = tokenize("(list \"é€\" x)\r\n  (f\r\n\"a\nb\" y)").unwrap();
+        let position = |i: usize| {
+            let context = &tokens[i].context;
+            (
+                context.line_number,
+                context.column_number,
+                context.offset,
+                context.length,
+            )
+        };
+        assert_eq!(position(2), (0, 6, 6, 4));
+        assert_eq!(position(3), (0, 11, 14, 1));
+        assert_eq!(position(5), (1, 2, 20, 1));
+        assert_eq!(position(7), (2, 0, 24, 5));
+        assert_eq!(position(8), (3, 3, 30, 1));
+        match tokens[7].content.as_ref() {
+            Some(TokenContent::String(s)) => assert_eq!(s, "a\nb"),
+            other => panic!("expected a string, got {:?}", other),
+        }
+
+        let err = eval("(list \"é\" (car 1))").unwrap_err();
+        let rendered = err.diagnostic(None).render("(list \"é\" (car 1))", false);
+        assert!(rendered.contains("1 | (list \"é\" (car 1))\n  |           ^^^^^^^\n"));
+        match eval("\"€\" #x") {
+            Err(EvalError::Tokenizing(err)) => assert_eq!(err.error_index, 4),
+            other => panic!("expected a tokenizing error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_spans() {
+        let tokens = tokenize("(define x 10)\n  (+ x\n  \"a\")").unwrap();
+        let root = parse(tokens, Some(Rc::from("main.lisp"))).unwrap();
+        let define = &root.children()[0];
+        let span = define.span.clone().unwrap();
+        assert_eq!((span.line, span.column, span.length), (1, 1, 13));
+        assert_eq!(span.file.as_deref(), Some("main.lisp"));
+        let span = define.children()[2].span.clone().unwrap();
+        assert_eq!((span.line, span.column, span.length), (1, 11, 2));
+        let span = root.children()[1].children()[1].span.clone().unwrap();
+        assert_eq!((span.line, span.column, span.length), (3, 3, 3));
+
+        let options = EvalOptions {
+            source_name: Some(Rc::from("main.lisp")),
+            ..Default::default()
+        };
+        let mut env = Environment::global();
+        let program = "(define x 10)\n(defun f (y)\n  (+ y undefined))\n(f x)";
+        let err = match eval_with_options(program, &mut env, &options) {
+            Err(EvalError::Runtime(err)) => err,
+            other => panic!("Expected a runtime error, got {:?}", other),
+        };
+        let span = err.span.clone().unwrap();
+        assert_eq!((span.line, span.column, span.length), (3, 8, 9));
+        assert_eq!(
+            err.to_string(),
+            "main.lisp:3:8: Unbound variable 'undefined'"
+        );
+
+        let err = eval("(car 1)").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "line 1, column 1: car expects a list, found 1"
+        );
+    }
+
+    #[test]
+    fn test_diagnostics() {
+        let source = "(define x 1)\n(+ x\n   missing)";
+        let err = eval(source).unwrap_err();
+        let rendered = err
+            .diagnostic(Some(Rc::from("main.lisp")))
+            .render(source, false);
+        assert_eq!(
+            rendered,
+            "error[E0103]: Unbound variable 'missing'\n \
+             --> main.lisp:3:4\n  \
+             |\n\
+             3 |    missing)\n  \
+             |    ^^^^^^^\n  \
+             = help: variables have to be defined with (define name value) before use\n"
+        );
+
+        let source = "(+ 1 \"abc";
+        let rendered = eval(source)
+            .unwrap_err()
+            .diagnostic(None)
+            .render(source, false);
+        assert!(rendered.starts_with("error[E0001]: Unclosed String\n --> <input>:1:6\n"));
+        assert!(rendered.ends_with("1 | (+ 1 \"abc\n  |      ^^^^\n"));
+
+        // Tabs are expanded so that the underline stays below the offending code
+        let source = "(define x 1)\n\t(+ x\t\"a\")";
+        let rendered = eval(source)
+            .unwrap_err()
+            .diagnostic(None)
+            .render(source, false);
+        assert!(rendered.ends_with("2 |     (+ x    \"a\")\n  |     ^^^^^^^^^^^^\n"));
+
+        let rendered = eval("(')")
+            .unwrap_err()
+            .diagnostic(None)
+            .render("(')", true);
+        assert!(rendered.contains("\x1b[1;31m^\x1b[0m"));
+    }
+
+    #[test]
+    fn test_error_kinds() {
+        let kind = |input: &str| match eval(input) {
+            Err(EvalError::Runtime(err)) => err.kind,
+            other => panic!("expected a runtime error, got {:?}", other),
+        };
+        let parse_error = |input: &str| matches!(eval(input), Err(EvalError::Parsing(_)));
+
+        assert_eq!(kind("(-)"), RuntimeErrorKind::Arity);
+        assert_eq!(kind("(car)"), RuntimeErrorKind::Arity);
+        assert_eq!(kind("(+ 1 \"a\")"), RuntimeErrorKind::TypeMismatch);
+        assert_eq!(kind("(- true)"), RuntimeErrorKind::TypeMismatch);
+        assert_eq!(kind("(1 2)"), RuntimeErrorKind::TypeMismatch);
+        assert_eq!(kind("(/ 1 0)"), RuntimeErrorKind::DivisionByZero);
+        assert_eq!(kind("(% 1 0)"), RuntimeErrorKind::DivisionByZero);
+        assert_eq!(kind("(^ 2 100000000)"), RuntimeErrorKind::Overflow);
+        assert_eq!(kind("(^ 2 5000000000)"), RuntimeErrorKind::Overflow);
+        assert_eq!(kind("(^ 1/2 (^ 2 100))"), RuntimeErrorKind::Overflow);
+        assert_eq!(
+            kind("undefined"),
+            RuntimeErrorKind::UnboundVariable("undefined".to_string())
+        );
+        assert_eq!(kind("(car '())"), RuntimeErrorKind::InvalidArgument);
+        assert_eq!(kind("(lambda)"), RuntimeErrorKind::Syntax);
+        assert_eq!(
+            kind("(defun f (n) (+ 1 (f n))) (f 1)"),
+            RuntimeErrorKind::RecursionLimit
+        );
+        // The limit counts function calls, and deep recursion does not depend on the size of
+        // the calling thread's stack
+        assert_eq!(
+            eval("(defun f (n) (if (= n 0) 0 (+ 1 (f (- n 1))))) (f 5000)").unwrap(),
+            Value::Int(5000)
+        );
+        set_recursion_limit(100);
+        assert_eq!(
+            eval("(defun f (n) (if (= n 0) 0 (+ 1 (f (- n 1))))) (f 99)").unwrap(),
+            Value::Int(99)
+        );
+        assert_eq!(
+            kind("(defun f (n) (if (= n 0) 0 (+ 1 (f (- n 1))))) (f 100)"),
+            RuntimeErrorKind::RecursionLimit
+        );
+        set_recursion_limit(DEFAULT_RECURSION_LIMIT);
+
+        assert_eq!(eval("(+ \"a\" \"b\")").unwrap(), Value::String("ab".into()));
+        assert_eq!(eval("(% -7 2)").unwrap(), Value::Int(1));
+        assert!(parse_error(")"));
+        assert!(parse_error("(+ 1 2))"));
+        assert!(parse_error("(+ 1 (* 2 3)"));
+        assert!(parse_error(&"(".repeat(10000)));
+    }
+
+    #[test]
+    fn test_bignums() {
+        let big = |digits: &str| Value::BigInt(digits.parse().unwrap());
+
+        assert_eq!(
+            eval("(* 99999999999 99999999999)").unwrap(),
+            big("9999999999800000000001")
+        );
+        assert_eq!(
+            eval("(^ 2 100)").unwrap(),
+            big("1267650600228229401496703205376")
+        );
+        assert_eq!(
+            eval("(+ 9223372036854775807 1)").unwrap(),
+            big("9223372036854775808")
+        );
+        assert_eq!(
+            eval("(- -9223372036854775808)").unwrap(),
+            big("9223372036854775808")
+        );
+        assert_eq!(
+            eval("(defun fact (n) (if (= n 0) 1 (* n (fact (- n 1))))) (fact 25)")
+                .unwrap()
+                .to_string(),
+            "15511210043330985984000000"
+        );
+
+        // Results that fit into an i64 again are demoted
+        assert_eq!(eval("(- (^ 2 64) (^ 2 64) -5)").unwrap(), Value::Int(5));
+        assert_eq!(eval("(/ (^ 10 30) (^ 10 28))").unwrap(), Value::Int(100));
+        assert_eq!(eval("(% (^ 10 30) 7)").unwrap(), Value::Int(1));
+        assert_eq!(
+            eval("(// (^ 10 20) 3)").unwrap(),
+            big("33333333333333333333")
+        );
+
+        // Long literals, in every base
+        assert_eq!(
+            eval("123456789012345678901234567890").unwrap(),
+            big("123456789012345678901234567890")
+        );
+        assert_eq!(eval("-9223372036854775808").unwrap(), Value::Int(i64::MIN));
+        assert_eq!(
+            eval("0xFFFFFFFFFFFFFFFFFF").unwrap(),
+            big("4722366482869645213695")
+        );
+
+        assert_eq!(
+            eval("(< (^ 2 70) (^ 2 71) (* (^ 2 71) 1.5))").unwrap(),
+            Value::Boolean(true)
+        );
+        assert_eq!(
+            eval("(= (^ 2 64) (* (^ 2 32) (^ 2 32)))").unwrap(),
+            Value::Boolean(true)
+        );
+        assert_eq!(
+            eval("(max 1 (^ 2 80))").unwrap(),
+            big("1208925819614629174706176")
+        );
+
+        // 0, 1 and -1 can be raised to exponents of any size
+        assert_eq!(eval("(^ 1 5000000000)").unwrap(), Value::Int(1));
+        assert_eq!(eval("(^ 0 (^ 2 100))").unwrap(), Value::Int(0));
+        assert_eq!(eval("(^ -1 5000000001)").unwrap(), Value::Int(-1));
+        assert_eq!(eval("(^ -1 (- (^ 2 100)))").unwrap(), Value::Int(1));
+        assert!(eval("(^ 0 -5000000000)").is_err());
+
+        // Exact numbers are compared with floats exactly, not after rounding them to a float
+        for (input, expected) in [
+            ("(= (^ 10 400) inf)", false),
+            ("(< (^ 10 400) inf)", true),
+            ("(> (- (^ 10 400)) -inf)", true),
+            ("(= (^ 10 400) nan)", false),
+            ("(= 9007199254740993 9007199254740992.0)", false),
+            ("(> 9007199254740993 9007199254740992.0)", true),
+            ("(= (^ 2 70) (* (^ 2 70) 1.0))", true),
+            ("(< 1/3 0.3333333333333333)", false),
+            ("(= 1/2 0.5)", true),
+        ] {
+            assert_eq!(eval(input).unwrap(), Value::Boolean(expected), "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_rationals() {
+        let rational = |input: &str| eval(input).unwrap().to_string();
+
+        assert_eq!(rational("(/ 1 3)"), "1/3");
+        assert_eq!(rational("(/ 6 4)"), "3/2");
+        assert_eq!(rational("(- 1/2)"), "-1/2");
+        assert_eq!(rational("(+ 1/3 1/6)"), "1/2");
+        assert_eq!(rational("(^ 2/3 3)"), "8/27");
+        assert_eq!(rational("(^ 2 -2)"), "1/4");
+        assert_eq!(rational("(% 7/2 1)"), "1/2");
+        assert_eq!(rational("(abs -1/3)"), "1/3");
+        assert_eq!(rational("(/ (^ 10 20) 3)"), "100000000000000000000/3");
+
+        // Whole results are integers again
+        assert_eq!(eval("(+ 1/3 2/3)").unwrap(), Value::Int(1));
+        assert_eq!(eval("(* 3/4 4)").unwrap(), Value::Int(3));
+        assert_eq!(eval("4/2").unwrap(), Value::Int(2));
+        assert_eq!(eval("(// 7/2 1)").unwrap(), Value::Int(3));
+        assert_eq!(eval("(numerator 6/4)").unwrap(), Value::Int(3));
+        assert_eq!(eval("(denominator 6/4)").unwrap(), Value::Int(2));
+
+        // Anything involving a float is inexact
+        assert_eq!(eval("(exact->inexact 1/4)").unwrap(), Value::Float(0.25));
+        assert_eq!(eval("(+ 1/4 0.5)").unwrap(), Value::Float(0.75));
+        assert_eq!(eval("(< 1/3 0.34 1/2)").unwrap(), Value::Boolean(true));
+        assert_eq!(eval("(= 1/3 (/ 2 6))").unwrap(), Value::Boolean(true));
+
+        // Repeated division is exact, unlike in floating point
+        assert_eq!(eval("(* (+ 1/10 2/10) 10)").unwrap(), Value::Int(3));
+
+        assert!(matches!(eval("1/0"), Err(EvalError::Tokenizing(_))));
+        assert!(matches!(eval("3/4.5"), Err(EvalError::Tokenizing(_))));
+        assert!(eval("(^ 0 -1)").is_err());
+    }
+
+    #[test]
+    fn test_exactness() {
+        // Integer arithmetic stays exact, anything involving a float stays inexact
+        assert_eq!(eval("(+ 2 3)").unwrap(), Value::Int(5));
+        assert_eq!(eval("(- 7.5 2.5)").unwrap(), Value::Float(5.0));
+        assert_eq!(eval("(/ 10.0 2)").unwrap(), Value::Float(5.0));
+        assert_eq!(eval("(^ 2.0 2)").unwrap(), Value::Float(4.0));
+        assert_eq!(eval("(// 7.5 2)").unwrap(), Value::Float(3.0));
+        assert_eq!(eval("(// -7 2.0)").unwrap(), Value::Float(-3.0));
+        assert!(matches!(eval("(// nan 1)").unwrap(), Value::Float(v) if v.is_nan()));
+        assert_eq!(
+            eval("(// 1e300 1e-300)").unwrap(),
+            Value::Float(f64::INFINITY)
+        );
+        assert_eq!(eval("(+ 2.5 2.5)").unwrap().to_string(), "5.0");
+        assert_eq!(eval("(* 1.0 (^ 10 30))").unwrap(), Value::Float(1e30));
+        assert_eq!(eval("(= 5 5.0 10/2)").unwrap(), Value::Boolean(true));
+
+        assert_eq!(eval("(round 2.5)").unwrap(), Value::Float(2.0));
+        assert_eq!(eval("(round 3.5)").unwrap(), Value::Float(4.0));
+        assert_eq!(eval("(round 5/2)").unwrap(), Value::Int(2));
+        assert_eq!(eval("(round -7/2)").unwrap(), Value::Int(-4));
+        assert_eq!(eval("(floor -7/2)").unwrap(), Value::Int(-4));
+        assert_eq!(eval("(floor 2.7)").unwrap(), Value::Float(2.0));
+        assert_eq!(eval("(ceiling 7/2)").unwrap(), Value::Int(4));
+        assert_eq!(eval("(truncate -3.7)").unwrap(), Value::Float(-3.0));
+        assert_eq!(eval("(round 7)").unwrap(), Value::Int(7));
+
+        assert_eq!(eval("(exact 2.0)").unwrap(), Value::Int(2));
+        assert_eq!(eval("(exact 2.5)").unwrap().to_string(), "5/2");
+        assert_eq!(eval("(exact (floor 2.7))").unwrap(), Value::Int(2));
+        assert_eq!(eval("(inexact 1/4)").unwrap(), Value::Float(0.25));
+        assert!(eval("(exact (/ 1.0 0))").is_err());
+        assert!(eval("(round \"a\")").is_err());
+
+        let mut env = Environment::global();
+        let options = EvalOptions {
+            collapse_floats: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            eval_with_options("(+ 2.5 2.5)", &mut env, &options).unwrap(),
+            Value::Int(5)
+        );
+        assert_eq!(
+            eval_with_options("(+ 2.5 2.6)", &mut env, &options).unwrap(),
+            Value::Float(5.1)
+        );
+        assert_eq!(eval("(+ 2.5 2.5)").unwrap(), Value::Float(5.0));
+    }
+
+    #[test]
+    fn test_number_literals() {
+        assert_eq!(eval("1e10").unwrap(), Value::Float(1e10));
+        assert_eq!(eval("6.02E23").unwrap(), Value::Float(6.02e23));
+        assert_eq!(eval("1.5e-3").unwrap(), Value::Float(0.0015));
+        assert_eq!(eval("2e+2").unwrap(), Value::Float(200.0));
+        assert_eq!(eval(".5").unwrap(), Value::Float(0.5));
+        assert_eq!(eval("-.5").unwrap(), Value::Float(-0.5));
+        assert_eq!(eval("+5").unwrap(), Value::Int(5));
+        assert_eq!(eval("(+ +5 -5)").unwrap(), Value::Int(0));
+        assert_eq!(eval("1_000_000").unwrap(), Value::Int(1_000_000));
+        assert_eq!(eval("0xFF_FF").unwrap(), Value::Int(0xFFFF));
+        assert_eq!(eval("0X1f").unwrap(), Value::Int(31));
+        assert_eq!(eval("0B101").unwrap(), Value::Int(5));
+        assert_eq!(eval("0o17").unwrap(), Value::Int(15));
+        assert_eq!(eval("0x1.8").unwrap(), Value::Float(1.5));
+        assert_eq!(eval("-0b11").unwrap(), Value::Int(-3));
+        assert_eq!(eval("inf").unwrap(), Value::Float(f64::INFINITY));
+        assert_eq!(eval("-inf").unwrap(), Value::Float(f64::NEG_INFINITY));
+        assert!(matches!(eval("nan").unwrap(), Value::Float(v) if v.is_nan()));
+        assert_eq!(eval("(define inf 1) (+ inf 1)").unwrap(), Value::Int(2));
+        assert_eq!(eval("'(inf -inf)").unwrap().to_string(), "(inf -inf)");
+        // NaN is unordered, every comparison with it is false except !=
+        for comparison in [
+            "(= 1 nan)",
+            "(= nan nan)",
+            "(< 1 nan)",
+            "(<= 1 nan)",
+            "(>= nan 1)",
+        ] {
+            assert_eq!(
+                eval(comparison).unwrap(),
+                Value::Boolean(false),
+                "{}",
+                comparison
+            );
+        }
+        assert_eq!(eval("(!= 1 nan)").unwrap(), Value::Boolean(true));
+        assert_eq!(eval("(= inf inf)").unwrap(), Value::Boolean(true));
+        assert!(matches!(eval("(max 1 nan)").unwrap(), Value::Float(v) if v.is_nan()));
+        assert!(matches!(eval("(min nan 1 2)").unwrap(), Value::Float(v) if v.is_nan()));
+        assert_eq!(eval("(- 5 3)").unwrap(), Value::Int(2));
+
+        let error = |input: &str| match eval(input) {
+            Err(EvalError::Tokenizing(err)) => (err.error_index, err.message),
+            other => panic!("expected a tokenizing error, got {:?}", other),
+        };
+        assert_eq!(
+            error("(+ 1 0o78)"),
+            (8, "Invalid digit '8' in octal number".to_string())
+        );
+        assert_eq!(
+            error("1__000"),
+            (
+                1,
+                "Invalid number: '_' must be placed between two digits".to_string()
+            )
+        );
+        assert_eq!(
+            error("1e"),
+            (
+                1,
+                "Invalid number: expected digits in the exponent".to_string()
+            )
+        );
+        assert_eq!(
+            error("1.2.3"),
+            (
+                3,
+                "Invalid number: multiple decimal points found".to_string()
+            )
+        );
+        assert_eq!(
+            error("12abc"),
+            (2, "Invalid digit 'a' in decimal number".to_string())
+        );
+        assert_eq!(
+            error("0x"),
+            (
+                1,
+                "Invalid number: expected digits after the '0x' prefix".to_string()
+            )
+        );
+        assert_eq!(error("1e5.5").0, 3);
+        assert_eq!(error("1-2").0, 1);
+        assert_eq!(error("1_").0, 1);
+    }
+
+    #[test]
+    fn test_error_handling() {
+        let mut env = Environment::global();
+        assert_eq!(
+            eval("(try (/ 1 0) (catch (e) (error-kind e)))").unwrap(),
+            Value::Symbol(Symbol::intern("division-by-zero"))
+        );
+        assert_eq!(
+            eval("(try (car 1) (catch (e) (error-message e)))").unwrap(),
+            Value::String("car expects a list, found 1".into())
+        );
+        assert_eq!(
+            eval("(try (raise 'oops) (catch (e) e))").unwrap(),
+            Value::Symbol(Symbol::intern("oops"))
+        );
+        assert_eq!(eval("(try 1 (catch (e) 2))").unwrap(), Value::Int(1));
+        assert_eq!(
+            eval("(error? (try x (catch (e) e)))").unwrap(),
+            Value::Boolean(true)
+        );
+
+        // Clauses with a kind only catch errors of that kind, errors can be raised again
+        assert_eq!(
+            eval("(try (error \"bad\" 1 2) (catch overflow (e) 0) (catch error (e) (error-irritants e)))")
+                .unwrap(),
+            Value::list(vec![Value::Int(1), Value::Int(2)])
+        );
+        assert_eq!(
+            eval("(try (try (car 1) (catch (e) (raise e))) (catch type-mismatch (e) 'outer))")
+                .unwrap(),
+            Value::Symbol(Symbol::intern("outer"))
+        );
+        let err = eval("(try (error \"bad input\" 42) (catch overflow (e) 0))").unwrap_err();
+        assert_eq!(err.message(), "bad input 42");
+        match err {
+            EvalError::Runtime(err) => {
+                assert_eq!(err.kind, RuntimeErrorKind::User([Value::Int(42)].into()))
+            }
+            other => panic!("expected a runtime error, got {:?}", other),
+        }
+
+        // Cleanup runs whether or not the body fails
+        eval_with_env("(define log '())", &mut env).unwrap();
+        assert_eq!(
+            eval_with_env(
+                "(try (/ 1 0) (catch (e) 'caught) (finally (set! log (cons 'a log))))",
+                &mut env
+            )
+            .unwrap(),
+            Value::Symbol(Symbol::intern("caught"))
+        );
+        assert!(
+            eval_with_env("(try (/ 1 0) (finally (set! log (cons 'b log))))", &mut env).is_err()
+        );
+        assert!(eval_with_env(
+            "(unwind-protect (car '()) (set! log (cons 'c log)))",
+            &mut env
+        )
+        .is_err());
+        assert_eq!(
+            eval_with_env("(unwind-protect 5 (set! log (cons 'd log)))", &mut env).unwrap(),
+            Value::Int(5)
+        );
+        assert_eq!(
+            eval_with_env("log", &mut env).unwrap().to_string(),
+            "(d c b a)"
+        );
+
+        assert!(eval("(try 1 (finally 2) (catch (e) 3))").is_err());
+        assert!(eval("(try 1 (catch e 3))").is_err());
+    }
+
+    #[test]
+    fn test_strings() {
+        let string = |input: &str| match eval(input).unwrap() {
+            Value::String(s) => s.to_string(),
+            other => panic!("expected a string, got {}", other),
+        };
+        assert_eq!(string(r#""say \"hi\"""#), "say \"hi\"");
+        assert_eq!(string(r#""a\tb\nc\\d\r\0""#), "a\tb\nc\\d\r\0");
+        assert_eq!(string(r#""\u{48}\u{1F600}""#), "H\u{1F600}");
+        assert_eq!(string("\"two\nlines\""), "two\nlines");
+        assert_eq!(string("\"one \\\n     line\""), "one line");
+        assert_eq!(string(r#"r"C:\path\n""#), "C:\\path\\n");
+        assert_eq!(string(r###"r#"say "hi""#"###), "say \"hi\"");
+        assert_eq!(eval(r#"(list "a\"b")"#).unwrap().to_string(), r#"("a\"b")"#);
+
+        // Tokens after a multi-line string keep their line numbers
+        let err = eval("(list \"a\nb\"\n  x)").unwrap_err();
+        let diagnostic = err.diagnostic(None);
+        let span = diagnostic.span.unwrap();
+        assert_eq!((span.line, span.column), (3, 3));
+
+        let error = |input: &str| match eval(input) {
+            Err(EvalError::Tokenizing(err)) => (err.line_number, err.error_index, err.message),
+            other => panic!("expected a tokenizing error, got {:?}", other),
+        };
+        assert_eq!(
+            error(r#""ab\q""#),
+            (0, 4, "Unknown escape sequence '\\q'".to_string())
+        );
+        assert_eq!(error("\"a\n\\x\"").0, 1);
+        assert_eq!(error(r#""\u{110000}""#).1, 10);
+        assert_eq!(error(r#""\u{12""#).1, 6);
+        assert_eq!(error(r#""\u41""#).1, 3);
+        assert_eq!(error("\"abc").2, "Unclosed String");
+        assert_eq!(error(r##"r#"abc""##).2, "Unclosed String");
+
+        // The REPL keeps reading lines until the input is complete
+        use crate::tokenize::is_complete;
+        for input in [
+            r#"r"C:\dir\""#,
+            r##"r#"a"b"#"##,
+            "'|a(b|",
+            "(f \"(\" #| ( |#)",
+            ")",
+        ] {
+            assert!(is_complete(input), "{}", input);
+        }
+        for input in [
+            "(f",
+            r#""a\""#,
+            r##"r#"a"b"##,
+            "'|a\n",
+            "#| #| |#",
+            "(car '(|)|)",
+        ] {
+            assert!(!is_complete(input), "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_malformed_input_does_not_panic() {
+        let fragments = [
+            "(",
+            ")",
+            "'",
+            "+",
+            "-",
+            "*",
+            "/",
+            "//",
+            "%",
+            "^",
+            "<",
+            "=",
+            "!=",
+            "0",
+            "1",
+            "-1",
+            "2.5",
+            "0x1F",
+            "0b",
+            "\"s\"",
+            "x",
+            "define",
+            "lambda",
+            "defun",
+            "let",
+            "if",
+            "cond",
+            "quote",
+            "set!",
+            "car",
+            "list",
+            "true",
+            "null",
+            "9223372036854775807",
+        ];
+        // A fixed linear congruential generator keeps the inputs reproducible
+        let mut seed: u64 = 0x2545_f491_4f6c_dd1d;
+        for _ in 0..2000 {
+            let mut input = String::new();
+            seed = seed
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            let len = (seed >> 33) % 12 + 1;
+            for _ in 0..len {
+                seed = seed
+                    .wrapping_mul(6364136223846793005)
+                    .wrapping_add(1442695040888963407);
+                input.push_str(fragments[(seed >> 33) as usize % fragments.len()]);
+                input.push(' ');
+            }
+            let _ = eval(&input);
+        }
+    }
 }
@@ -5,6 +5,9 @@ pub mod errors;
 pub mod eval;
 pub mod operatortype;
 pub mod parse;
+pub mod span;
+pub mod symbol;
 pub mod token;
 pub mod tokenize;
+pub mod trace;
 pub mod value;
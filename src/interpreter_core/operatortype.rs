@@ -1,3 +1,9 @@
+use std::cmp::Ordering;
+use std::fmt;
+
+use crate::errors::RuntimeError;
+use crate::value::Value;
+
 #[derive(Debug, Clone)]
 pub enum Operator {
     Plus,
@@ -14,3 +20,154 @@ pub enum Operator {
     Lt,
     Gt,
 }
+
+impl Operator {
+    pub fn from_symbol(symbol: &str) -> Option<Operator> {
+        let operator = match symbol {
+            "+" => Operator::Plus,
+            "-" => Operator::Minus,
+            "/" => Operator::Div,
+            "//" => Operator::IntDiv,
+            "*" => Operator::Mul,
+            "%" => Operator::Modulo,
+            "^" => Operator::Power,
+            "=" => Operator::Eq,
+            "!=" => Operator::Neq,
+            ">=" => Operator::Geq,
+            "<=" => Operator::Leq,
+            "<" => Operator::Lt,
+            ">" => Operator::Gt,
+            _ => return None,
+        };
+        Some(operator)
+    }
+
+    /// Applies the operator to the evaluated `args`, direct calls like (+ 1 2) and the builtin
+    /// procedures bound to the operator names share this.
+    pub fn apply(&self, args: &[Value]) -> Result<Value, RuntimeError> {
+        let Some((first, rest)) = args.split_first() else {
+            return match self {
+                Operator::Plus => Ok(Value::Int(0)),
+                Operator::Mul => Ok(Value::Int(1)),
+                Operator::Lt | Operator::Leq | Operator::Gt | Operator::Geq | Operator::Neq => {
+                    Ok(Value::Boolean(true))
+                }
+                _ => Err(RuntimeError::arity(format!(
+                    "{} expects at least 1 argument(s), got 0",
+                    self
+                ))),
+            };
+        };
+
+        match self {
+            Operator::Plus => plus(first, rest),
+            Operator::Minus => minus(first, rest),
+            Operator::Mul => mul(first, rest),
+            Operator::Modulo => fold(first, rest, Value::checked_rem),
+            Operator::Div => fold(first, rest, Value::checked_div),
+            Operator::IntDiv => fold(first, rest, Value::checked_int_div),
+            Operator::Power => fold(first, rest, Value::checked_pow),
+            Operator::Eq => equal(first, rest),
+            Operator::Lt => ordered(args, Ordering::is_lt),
+            Operator::Leq => ordered(args, Ordering::is_le),
+            Operator::Gt => ordered(args, Ordering::is_gt),
+            Operator::Geq => ordered(args, Ordering::is_ge),
+            Operator::Neq => distinct(args),
+        }
+    }
+}
+
+fn fold(
+    first: &Value,
+    rest: &[Value],
+    op: fn(Value, Value) -> Result<Value, RuntimeError>,
+) -> Result<Value, RuntimeError> {
+    rest.iter()
+        .try_fold(first.clone(), |value, arg| op(value, arg.clone()))
+}
+
+fn plus(first: &Value, rest: &[Value]) -> Result<Value, RuntimeError> {
+    if !(first.is_numeric() || matches!(first, Value::String(_))) {
+        return Err(RuntimeError::type_mismatch(format!(
+            "+ expects numbers or strings, found {}",
+            first
+        )));
+    }
+    fold(first, rest, Value::checked_add)
+}
+
+fn minus(first: &Value, rest: &[Value]) -> Result<Value, RuntimeError> {
+    if rest.is_empty() {
+        return first.clone().checked_negate();
+    }
+    fold(first, rest, Value::checked_sub)
+}
+
+fn mul(first: &Value, rest: &[Value]) -> Result<Value, RuntimeError> {
+    if !first.is_numeric() {
+        return Err(RuntimeError::type_mismatch(format!(
+            "* expects numbers, found {}",
+            first
+        )));
+    }
+    fold(first, rest, Value::checked_mul)
+}
+
+fn equal(first: &Value, rest: &[Value]) -> Result<Value, RuntimeError> {
+    for other in rest {
+        // Numbers are equal by value regardless of exactness, e.g. (= 5 5.0)
+        let equal = if first.is_numeric() && other.is_numeric() {
+            first.compare_to(other)? == Some(Ordering::Equal)
+        } else {
+            other == first
+        };
+        if !equal {
+            return Ok(Value::Boolean(false));
+        }
+    }
+    Ok(Value::Boolean(true))
+}
+
+/// Whether `holds` is true for the ordering of every pair of neighbouring arguments
+fn ordered(args: &[Value], holds: fn(Ordering) -> bool) -> Result<Value, RuntimeError> {
+    for pair in args.windows(2) {
+        // Comparisons with NaN are always false
+        match pair[0].compare_to(&pair[1])? {
+            Some(ordering) if holds(ordering) => {}
+            _ => return Ok(Value::Boolean(false)),
+        }
+    }
+    Ok(Value::Boolean(true))
+}
+
+fn distinct(args: &[Value]) -> Result<Value, RuntimeError> {
+    for (i, next) in args.iter().enumerate() {
+        for value in &args[..i] {
+            if value.compare_to(next)? == Some(Ordering::Equal) {
+                return Ok(Value::Boolean(false));
+            }
+        }
+    }
+    Ok(Value::Boolean(true))
+}
+
+impl fmt::Display for Operator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let symbol = match self {
+            Operator::Plus => "+",
+            Operator::Minus => "-",
+            Operator::Div => "/",
+            Operator::IntDiv => "//",
+            Operator::Mul => "*",
+            Operator::Modulo => "%",
+            Operator::Power => "^",
+            Operator::Eq => "=",
+            Operator::Neq => "!=",
+            Operator::Geq => ">=",
+            Operator::Leq => "<=",
+            Operator::Lt => "<",
+            Operator::Gt => ">",
+        };
+        write!(f, "{}", symbol)
+    }
+}
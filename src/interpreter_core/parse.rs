@@ -1,17 +1,38 @@
 use crate::ast::{AstNode, AstNodeValue};
 use crate::errors::ParsingError;
+use crate::span::Span;
 use crate::token::{Token, TokenContent, TokenType};
 use crate::value::Value;
+use std::rc::Rc;
+
+/// Nesting beyond this depth is rejected instead of overflowing the stack.
+const MAX_NESTING: usize = 256;
 
 struct ParserState {
     tokens: Vec<Token>,
     next_token_idx: usize,
+    source_name: Option<Rc<str>>,
+    depth: usize,
 }
 impl ParserState {
-    pub fn new(tokens: Vec<Token>) -> ParserState {
+    pub fn new(tokens: Vec<Token>, source_name: Option<Rc<str>>) -> ParserState {
         ParserState {
             tokens,
             next_token_idx: 0,
+            source_name,
+            depth: 0,
+        }
+    }
+
+    pub fn span(&self, token: &Token) -> Span {
+        Span::from_context(&token.context, self.source_name.clone())
+    }
+
+    /// Extends `span` up to the most recently consumed token.
+    pub fn span_to_previous(&self, span: Span) -> Span {
+        match self.next_token_idx.checked_sub(1) {
+            Some(idx) => span.to(&self.tokens[idx].context),
+            None => span,
         }
     }
 
@@ -31,126 +52,250 @@ impl ParserState {
     }
 }
 
-fn number(token: &Token, parent: &mut AstNode) {
+fn number(token: &Token, parent: &mut AstNode, span: Span) {
     let content = token.content.clone().unwrap();
 
     let ast_value = match content {
         TokenContent::Float(v) => AstNodeValue::Literal(Value::Float(v)),
         TokenContent::Int(v) => AstNodeValue::Literal(Value::Int(v)),
+        TokenContent::BigInt(v) => AstNodeValue::Literal(Value::BigInt(v)),
+        TokenContent::Rational(v) => AstNodeValue::Literal(Value::from_rational(v)),
         _ => unreachable!(),
     };
 
-    let node = AstNode::new(ast_value);
+    let node = AstNode::with_span(ast_value, span);
     parent.add_child(node);
 }
 
-fn identifier(
-    parser: &mut ParserState,
-    parent: &mut AstNode,
-    token: Token,
-) -> Result<(), ParsingError> {
+fn identifier(token: &Token, parent: &mut AstNode, span: Span) {
     let content = token.content.clone().unwrap();
 
     if let TokenContent::String(v) = content {
-        // By default, everything following an identifier, will be the identifier's child
-        let ast_value = AstNodeValue::Identifier(v);
-        let mut node = AstNode::new(ast_value);
-        _parse(parser, &mut node)?;
+        let quoted = matches!(token.token_type, TokenType::QuotedIdentifier);
+        let ast_value = match v.as_str() {
+            _ if quoted => AstNodeValue::Identifier(v),
+            "true" => AstNodeValue::Literal(Value::Boolean(true)),
+            "false" => AstNodeValue::Literal(Value::Boolean(false)),
+            "null" => AstNodeValue::Literal(Value::None),
+            _ => AstNodeValue::Identifier(v),
+        };
+        let node = AstNode::with_span(ast_value, span);
         parent.add_child(node);
     } else {
         unreachable!();
     }
-    Ok(())
 }
 
-fn string(token: &Token, parent: &mut AstNode) {
+fn string(token: &Token, parent: &mut AstNode, span: Span) {
     let content = token.content.clone().unwrap();
 
     if let TokenContent::String(v) = content {
-        let ast_value = AstNodeValue::Literal(Value::String(v));
-        let node = AstNode::new(ast_value);
+        let ast_value = AstNodeValue::Literal(Value::String(v.into()));
+        let node = AstNode::with_span(ast_value, span);
         parent.add_child(node);
     } else {
         unreachable!();
     }
 }
 
-fn expression(parser: &mut ParserState, parent: &mut AstNode) -> Result<(), ParsingError> {
-    if let Some(token) = parser.peek() {
-        if let Some(TokenContent::Operator(operator)) = token.content.clone() {
+fn expression(
+    parser: &mut ParserState,
+    parent: &mut AstNode,
+    token: Token,
+    span: Span,
+) -> Result<(), ParsingError> {
+    if parser.depth >= MAX_NESTING {
+        return Err(ParsingError::new(
+            Some(token),
+            "Expressions are nested too deeply",
+        ));
+    }
+    parser.depth += 1;
+    skip_datum_comments(parser)?;
+    if let Some(next_tok) = parser.peek() {
+        if let Some(TokenContent::Operator(operator)) = next_tok.content.clone() {
             let mut operator_node = AstNode::new(AstNodeValue::Operator(operator));
             let _ = parser.advance();
-            _parse(parser, &mut operator_node)?;
+            _parse(parser, &mut operator_node, Some(&token))?;
+            operator_node.span = Some(parser.span_to_previous(span));
             parent.add_child(operator_node);
         } else {
-            _parse(parser, parent)?;
+            // Any other form keeps its head as the first child, e.g. (define x 10)
+            let mut expression_node = AstNode::new(AstNodeValue::Expression);
+            _parse(parser, &mut expression_node, Some(&token))?;
+            expression_node.span = Some(parser.span_to_previous(span));
+            parent.add_child(expression_node);
         }
     } else {
-        let err = ParsingError::new(None, "Expected token after '(' found EOF");
+        let err = ParsingError::new(Some(token), "Expected token after '(' found EOF");
         return Err(err);
     }
+    parser.depth -= 1;
     Ok(())
 }
 
+fn operator(token: &Token, parent: &mut AstNode, span: Span) {
+    let content = token.content.clone().unwrap();
+
+    // Outside of head position an operator is just a name, e.g. '(+ a b) or '*
+    if let TokenContent::Operator(op) = content {
+        let node = AstNode::with_span(AstNodeValue::Identifier(op.to_string()), span);
+        parent.add_child(node);
+    } else {
+        unreachable!();
+    }
+}
+
 fn quoted_expression(
     parser: &mut ParserState,
     parent: &mut AstNode,
     token: Token,
+    span: Span,
 ) -> Result<(), ParsingError> {
-    if let None = parser.peek() {
-        return Err(ParsingError::new(Some(token), "Expected token after '"));
+    skip_datum_comments(parser)?;
+    let next_tok = match parser.advance().cloned() {
+        Some(next_tok) => next_tok,
+        None => return Err(ParsingError::new(Some(token), "Expected token after '")),
+    };
+    if let TokenType::Rparen = next_tok.token_type {
+        return Err(ParsingError::new(
+            Some(next_tok),
+            "Expected expression after ', found ')'",
+        ));
     }
 
-    match parser.peek() {
-        Some(next_tok) => match next_tok.token_type {
-            TokenType::Lparen => {}
-            _ => {
-                return Err(ParsingError::new(
-                    Some(next_tok.clone()),
-                    format!("Expected '(' after ' , found {:?}", next_tok).as_str(),
-                ))
-            }
-        },
-        None => {
+    let mut node = AstNode::new(AstNodeValue::Quote);
+    datum(parser, &mut node, next_tok)?;
+    node.span = Some(parser.span_to_previous(span));
+    parent.add_child(node);
+
+    Ok(())
+}
+
+/// Whether `token` is the dot of a dotted pair, |.| is a symbol like any other
+fn is_dot(token: &Token) -> bool {
+    matches!(token.token_type, TokenType::Identifier)
+        && matches!(&token.content, Some(TokenContent::String(s)) if s == ".")
+}
+
+/// Parses the datum after the `dot` of a list like (1 . 2) into `parent`, followed by the `)`
+/// that closes the list.
+fn dotted_tail(
+    parser: &mut ParserState,
+    parent: &mut AstNode,
+    dot: Token,
+) -> Result<(), ParsingError> {
+    // The head of an operator form is the node itself, e.g. in '(+ . 1)
+    if matches!(parent.value, AstNodeValue::Expression) && parent.children().is_empty() {
+        return Err(ParsingError::new(
+            Some(dot),
+            "Expected an expression before '.'",
+        ));
+    }
+    let span = parser.span(&dot);
+    skip_datum_comments(parser)?;
+    let mut tail = AstNode::new(AstNodeValue::DottedTail);
+    match parser.advance().cloned() {
+        Some(token) if !matches!(token.token_type, TokenType::Rparen) && !is_dot(&token) => {
+            datum(parser, &mut tail, token)?
+        }
+        _ => {
             return Err(ParsingError::new(
-                Some(token.clone()),
-                "Expected token after '",
+                Some(dot),
+                "Expected an expression after '.'",
             ))
         }
     }
-    let mut node = AstNode::new(AstNodeValue::Quote);
+    tail.span = Some(parser.span_to_previous(span));
+    parent.add_child(tail);
 
-    _parse(parser, &mut node)?;
-    parent.add_child(node);
+    skip_datum_comments(parser)?;
+    match parser.advance().cloned() {
+        Some(token) if matches!(token.token_type, TokenType::Rparen) => Ok(()),
+        _ => Err(ParsingError::new(
+            Some(dot),
+            "Expected ')' after the expression following '.'",
+        )),
+    }
+}
 
+fn datum(parser: &mut ParserState, parent: &mut AstNode, token: Token) -> Result<(), ParsingError> {
+    if is_dot(&token) {
+        return Err(ParsingError::new(
+            Some(token),
+            "Unexpected '.', only a list can have a dotted tail like (1 . 2)",
+        ));
+    }
+    let span = parser.span(&token);
+    match token.token_type {
+        TokenType::Lparen => expression(parser, parent, token, span)?,
+        TokenType::Number => number(&token, parent, span),
+        TokenType::Identifier | TokenType::QuotedIdentifier => identifier(&token, parent, span),
+        TokenType::String => string(&token, parent, span),
+        TokenType::Operator => operator(&token, parent, span),
+        TokenType::Quote => quoted_expression(parser, parent, token, span)?,
+        TokenType::Rparen | TokenType::DatumComment => unreachable!(),
+    };
     Ok(())
 }
 
-fn _parse(parser: &mut ParserState, parent: &mut AstNode) -> Result<(), ParsingError> {
-    while let Some(token) = parser.advance() {
-        match token.token_type {
-            TokenType::Rparen => return Ok(()),
-            TokenType::Lparen => expression(parser, parent)?,
-            TokenType::Number => number(token, parent),
-            TokenType::Identifier => {
-                let token_copy = token.clone();
-                identifier(parser, parent, token_copy)?;
+/// Parses and drops the expressions commented out by `#;`. In `#; #; a b` both `a` and `b`
+/// are commented out.
+fn skip_datum_comments(parser: &mut ParserState) -> Result<(), ParsingError> {
+    while let Some(TokenType::DatumComment) = parser.peek().map(|token| token.token_type) {
+        let comment = parser.advance().cloned();
+        skip_datum_comments(parser)?;
+        match parser.advance().cloned() {
+            Some(token) if !matches!(token.token_type, TokenType::Rparen) => {
+                let mut discarded = AstNode::new(AstNodeValue::Root);
+                datum(parser, &mut discarded, token)?;
             }
-            TokenType::String => string(token, parent),
-            TokenType::Quote => {
-                let token_copy = token.clone();
-                quoted_expression(parser, parent, token_copy)?;
+            _ => {
+                return Err(ParsingError::new(
+                    comment,
+                    "Expected an expression to comment out after '#;'",
+                ))
             }
-            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Parses data into `parent` until the `)` matching `open`, or until EOF at the top level.
+fn _parse(
+    parser: &mut ParserState,
+    parent: &mut AstNode,
+    open: Option<&Token>,
+) -> Result<(), ParsingError> {
+    loop {
+        skip_datum_comments(parser)?;
+        let Some(token) = parser.advance().cloned() else {
+            break;
         };
+        if let TokenType::Rparen = token.token_type {
+            if open.is_none() {
+                return Err(ParsingError::new(Some(token), "Unexpected ')'"));
+            }
+            return Ok(());
+        }
+        if open.is_some() && is_dot(&token) {
+            return dotted_tail(parser, parent, token);
+        }
+        datum(parser, parent, token)?;
     }
 
-    Ok(())
+    match open {
+        Some(open) => Err(ParsingError::new(
+            Some(open.clone()),
+            "Unclosed '(', expected ')' before EOF",
+        )),
+        None => Ok(()),
+    }
 }
 
-pub fn parse(tokens: Vec<Token>) -> Result<AstNode, ParsingError> {
-    let mut parser = ParserState::new(tokens);
+pub fn parse(tokens: Vec<Token>, source_name: Option<Rc<str>>) -> Result<AstNode, ParsingError> {
+    let mut parser = ParserState::new(tokens, source_name);
     let mut root = AstNode::new(AstNodeValue::Root);
-    _parse(&mut parser, &mut root)?;
+    _parse(&mut parser, &mut root, None)?;
     Ok(root)
 }
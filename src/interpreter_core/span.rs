@@ -0,0 +1,41 @@
+use std::fmt;
+use std::rc::Rc;
+
+use crate::token::TokenContext;
+
+/// A region of source code. Lines and columns are 1-based, columns and the length count chars.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Span {
+    pub file: Option<Rc<str>>,
+    pub line: usize,
+    pub column: usize,
+    pub length: usize,
+}
+
+impl Span {
+    pub fn from_context(context: &TokenContext, file: Option<Rc<str>>) -> Span {
+        Span {
+            file,
+            line: context.line_number + 1,
+            column: context.column_number + 1,
+            length: context.length,
+        }
+    }
+
+    /// Extends the span up to the end of `end`. Spans crossing lines keep their length.
+    pub fn to(mut self, end: &TokenContext) -> Span {
+        if end.line_number + 1 == self.line && end.column_number + 1 >= self.column {
+            self.length = end.column_number + 1 + end.length - self.column;
+        }
+        self
+    }
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.file {
+            Some(file) => write!(f, "{}:{}:{}", file, self.line, self.column),
+            None => write!(f, "line {}, column {}", self.line, self.column),
+        }
+    }
+}
@@ -0,0 +1,42 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{LazyLock, Mutex};
+
+#[derive(Default)]
+struct SymbolTable {
+    names: Vec<&'static str>,
+    ids: HashMap<&'static str, usize>,
+}
+
+static SYMBOL_TABLE: LazyLock<Mutex<SymbolTable>> =
+    LazyLock::new(|| Mutex::new(SymbolTable::default()));
+
+/// An interned name. Two symbols with the same name share the same id, so
+/// comparing them is a plain integer comparison.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Symbol(usize);
+
+impl Symbol {
+    pub fn intern(name: &str) -> Symbol {
+        let mut table = SYMBOL_TABLE.lock().unwrap();
+        if let Some(&id) = table.ids.get(name) {
+            return Symbol(id);
+        }
+        // Interned names live for the rest of the program
+        let name: &'static str = Box::leak(name.to_string().into_boxed_str());
+        let id = table.names.len();
+        table.names.push(name);
+        table.ids.insert(name, id);
+        Symbol(id)
+    }
+
+    pub fn name(self) -> &'static str {
+        SYMBOL_TABLE.lock().unwrap().names[self.0]
+    }
+}
+
+impl fmt::Display for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
@@ -1,4 +1,6 @@
 use crate::operatortype::Operator;
+use num_bigint::BigInt;
+use num_rational::BigRational;
 
 #[derive(Debug, Clone, Copy)]
 pub enum TokenType {
@@ -7,14 +9,23 @@ pub enum TokenType {
     Operator,
     Number,
     Identifier,
+    /// `|...|`, an identifier that is never read as a literal like `true`
+    QuotedIdentifier,
     String,
     Quote,
+    /// `#;`, comments out the expression that follows it
+    DatumComment,
 }
 
 #[derive(Debug, Clone)]
 pub struct TokenContext {
     pub line_number: usize,
+    /// Counted in chars from the start of the line
     pub column_number: usize,
+    /// Byte offset of the token in the source
+    pub offset: usize,
+    /// Counted in chars
+    pub length: usize,
 }
 
 #[derive(Debug, Clone)]
@@ -28,6 +39,8 @@ pub struct Token {
 pub enum TokenContent {
     Float(f64),
     Int(i64),
+    BigInt(BigInt),
+    Rational(BigRational),
     String(String),
     Operator(Operator),
 }
@@ -1,288 +1,590 @@
 use crate::errors::TokenizingError;
-use crate::operatortype::Operator::{
-    Div, Eq, Geq, Gt, IntDiv, Leq, Lt, Minus, Modulo, Mul, Neq, Plus, Power,
-};
+use crate::operatortype;
 use crate::token::TokenContext;
 use crate::token::TokenType::{Lparen, Number, Operator, Rparen};
 use crate::token::{Token, TokenContent, TokenType};
 
 use crate::value::NumBase;
+use num_bigint::BigInt;
+use num_rational::BigRational;
+use num_traits::{ToPrimitive, Zero};
+use std::iter::Peekable;
+use std::str::CharIndices;
 
-fn parse_number<I>(
-    input: &mut std::iter::Peekable<I>,
-    first_char: char,
-    context: &TokenContext,
-) -> Result<TokenContent, TokenizingError>
-where
-    I: std::iter::Iterator<Item = (usize, char)>,
-{
-    let mut float = false;
-    let mut base = NumBase::Dec;
-    let mut num_str = String::new();
-    let mut sign = 1;
-    let mut contains_sign = false;
-
-    let digit_after_sign = if first_char == '-' {
-        let (_, c) = input.next().unwrap();
-        sign = -1;
-        contains_sign = true;
-        c
-    } else {
-        first_char
+/// Whether a number literal starts at the beginning of `s`, e.g. 5, -5, +.5 or -inf
+fn starts_number(s: &str) -> bool {
+    let unsigned = s.strip_prefix(['+', '-']).unwrap_or(s);
+    let mut chars = unsigned.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_digit() => true,
+        Some('.') => chars.next().is_some_and(|c| c.is_ascii_digit()),
+        // inf and nan without a sign are lexed as identifiers, they are bound like pi
+        _ => unsigned.len() < s.len() && (is_word(unsigned, "inf") || is_word(unsigned, "nan")),
+    }
+}
+
+fn is_word(s: &str, word: &str) -> bool {
+    s.strip_prefix(word)
+        .is_some_and(|rest| !rest.starts_with(is_symbol_char))
+}
+
+/// Length in bytes of the number literal at the start of `s`. Everything up to the next
+/// delimiter belongs to it, so that e.g. 12abc is reported as a malformed number.
+fn number_len(s: &str) -> usize {
+    s.char_indices()
+        .skip(1)
+        .find(|&(_, c)| !(c.is_alphanumeric() || matches!(c, '.' | '_' | '/' | '+' | '-')))
+        .map_or(s.len(), |(i, _)| i)
+}
+
+fn parse_number(lexeme: &str, context: &TokenContext) -> Result<TokenContent, TokenizingError> {
+    // Offsets into the lexeme are in bytes, columns are counted in chars
+    let error = |offset: usize, message: &str| {
+        TokenizingError::new(
+            context.line_number,
+            context.column_number,
+            context.column_number + lexeme[..offset].chars().count(),
+            message,
+        )
     };
-    num_str.push(digit_after_sign);
-
-    if digit_after_sign == '0' {
-        if let Some(&(_, nextc)) = input.peek() {
-            match nextc {
-                'x' => {
-                    num_str.push(nextc);
-                    input.next();
-                    base = NumBase::Hex;
-                }
-                'b' => {
-                    num_str.push(nextc);
-                    input.next();
-                    base = NumBase::Bin;
-                }
-                'o' => {
-                    num_str.push(nextc);
-                    input.next();
-                    base = NumBase::Oct;
-                }
-                _ => {}
-            }
-        }
+
+    let (negative, body_start) = match lexeme.as_bytes()[0] {
+        b'-' => (true, 1),
+        b'+' => (false, 1),
+        _ => (false, 0),
+    };
+    match &lexeme[body_start..] {
+        "inf" if negative => return Ok(TokenContent::Float(f64::NEG_INFINITY)),
+        "inf" => return Ok(TokenContent::Float(f64::INFINITY)),
+        "nan" => return Ok(TokenContent::Float(f64::NAN)),
+        _ => {}
     }
-    let mut end_idx = context.column_number;
-    while let Some(&(i, c)) = input.peek() {
-        if !base.is_valid_digit(c) && c != '.' && c != '-' {
-            break;
-        }
-        end_idx = i;
-        if c == '-' {
-            if contains_sign {
-                return Err(TokenizingError::new(
-                    context.line_number,
-                    context.column_number,
-                    i,
-                    "Invalid number: multiple minus signs found",
+
+    let mut base = NumBase::Dec;
+    let mut digits_start = body_start;
+    if let [b'0', prefix, ..] = &lexeme.as_bytes()[body_start..] {
+        if let Some(prefixed) = NumBase::from_prefix(*prefix as char) {
+            base = prefixed;
+            digits_start += 2;
+            if digits_start == lexeme.len() {
+                let prefix = &lexeme[body_start..];
+                return Err(error(
+                    lexeme.len() - 1,
+                    &format!(
+                        "Invalid number: expected digits after the '{}' prefix",
+                        prefix
+                    ),
                 ));
             }
-            contains_sign = true;
         }
-        if c == '.' {
-            if float {
-                return Err(TokenizingError::new(
-                    context.line_number,
-                    context.column_number,
-                    i,
+    }
+
+    // Separators are dropped, the positions of '.', 'e' and '/' refer to the cleaned digits
+    let mut digits = String::new();
+    let mut point = None;
+    let mut exponent = None;
+    let mut slash = None;
+    let mut prev = None;
+    for (i, c) in lexeme[digits_start..].char_indices() {
+        let offset = digits_start + i;
+        let next = lexeme[offset + c.len_utf8()..].chars().next();
+        match c {
+            '_' => {
+                let is_digit = |c: Option<char>| c.is_some_and(|c| base.is_valid_digit(c));
+                if !is_digit(prev) || !is_digit(next) {
+                    return Err(error(
+                        offset,
+                        "Invalid number: '_' must be placed between two digits",
+                    ));
+                }
+                prev = Some(c);
+                continue;
+            }
+            '.' if point.is_some() => {
+                return Err(error(
+                    offset,
                     "Invalid number: multiple decimal points found",
-                ));
+                ))
             }
-            if NumBase::Dec != base {
-                return Err(TokenizingError::new(
-                    context.line_number,
-                    context.column_number,
-                    i,
-                    "Floats only support decimal numbers",
-                ));
+            '.' if exponent.is_some() => {
+                return Err(error(
+                    offset,
+                    "Invalid number: the exponent must be an integer",
+                ))
+            }
+            '.' if slash.is_some() => {
+                return Err(error(
+                    offset,
+                    "Invalid number: rationals cannot contain a decimal point",
+                ))
+            }
+            '.' => point = Some(digits.len()),
+            'e' | 'E' if base == NumBase::Dec => {
+                if exponent.is_some() {
+                    return Err(error(offset, "Invalid number: multiple exponents found"));
+                }
+                if slash.is_some() {
+                    return Err(error(
+                        offset,
+                        "Invalid number: rationals cannot have an exponent",
+                    ));
+                }
+                exponent = Some(digits.len());
+            }
+            '+' | '-' if matches!(prev, Some('e' | 'E')) && exponent.is_some() => {}
+            '+' | '-' => {
+                return Err(error(
+                    offset,
+                    &format!(
+                        "Invalid number: '{}' is only allowed at the start or after an exponent",
+                        c
+                    ),
+                ))
+            }
+            '/' if base != NumBase::Dec || point.is_some() || exponent.is_some() => {
+                return Err(error(
+                    offset,
+                    "Rationals must be written as two decimal integers, e.g. 3/4",
+                ))
+            }
+            '/' if slash.is_some() => {
+                return Err(error(offset, "Invalid number: multiple '/' found"))
+            }
+            '/' => slash = Some(digits.len()),
+            c if base.is_valid_digit(c) => {}
+            c => {
+                return Err(error(
+                    offset,
+                    &format!("Invalid digit '{}' in {} number", c, base.name()),
+                ))
             }
-            float = true;
         }
-        num_str.push(c);
-        input.next();
+        digits.push(c);
+        prev = Some(c);
+    }
+
+    let end = lexeme.len() - 1;
+    if let Some(exponent) = exponent {
+        if !digits[exponent + 1..].contains(|c: char| c.is_ascii_digit()) {
+            return Err(error(
+                end,
+                "Invalid number: expected digits in the exponent",
+            ));
+        }
+    }
+    if point.is_some() && !digits.contains(|c: char| base.is_valid_digit(c)) {
+        return Err(error(
+            end,
+            "Invalid number: expected digits around the decimal point",
+        ));
     }
 
-    let content = if float {
-        match base.parse_float(&num_str) {
-            Ok(parsed_float) => TokenContent::Float(sign as f64 * parsed_float),
-            Err(_) => {
-                return Err(TokenizingError::new(
-                    context.line_number,
-                    context.column_number,
-                    end_idx,
-                    format!("'{}' cannot be parsed as float", num_str).as_str(),
+    let content = if let Some(slash) = slash {
+        let (numer, denom) = (&digits[..slash], &digits[slash + 1..]);
+        let (Ok(numer), Ok(denom)) = (numer.parse::<BigInt>(), denom.parse::<BigInt>()) else {
+            return Err(error(end, "Invalid number: expected digits after '/'"));
+        };
+        if denom.is_zero() {
+            return Err(error(
+                end,
+                "Invalid number: the denominator of a rational cannot be 0",
+            ));
+        }
+        let parsed = BigRational::new(numer, denom);
+        TokenContent::Rational(if negative { -parsed } else { parsed })
+    } else if point.is_some() || exponent.is_some() {
+        match base.parse_float(&digits) {
+            Some(parsed) => TokenContent::Float(if negative { -parsed } else { parsed }),
+            None => {
+                return Err(error(
+                    end,
+                    &format!("'{}' cannot be parsed as float", lexeme),
                 ))
             }
         }
     } else {
-        match base.parse_int(&num_str) {
-            Ok(parsed_int) => TokenContent::Int(sign * parsed_int),
-            Err(_) => {
-                return Err(TokenizingError::new(
-                    context.line_number,
-                    context.column_number,
-                    end_idx,
-                    format!("'{}' cannot be parsed as int", num_str).as_str(),
-                ))
-            }
+        match base.parse_int(&digits) {
+            Ok(parsed) if negative => TokenContent::Int(-parsed),
+            Ok(parsed) => TokenContent::Int(parsed),
+            // Literals too long for an i64 become bignums, i64::MIN only fits once negated
+            Err(_) => match base.parse_bigint(&digits) {
+                Some(parsed) => {
+                    let parsed = if negative { -parsed } else { parsed };
+                    match parsed.to_i64() {
+                        Some(v) => TokenContent::Int(v),
+                        None => TokenContent::BigInt(parsed),
+                    }
+                }
+                None => return Err(error(end, &format!("'{}' cannot be parsed as int", lexeme))),
+            },
         }
     };
 
     Ok(content)
 }
 
-fn parse_string<I>(input: I, context: &TokenContext) -> Result<TokenContent, TokenizingError>
-where
-    I: IntoIterator<Item = (usize, char)>,
-{
+/// Walks the source one char at a time while keeping track of the line, the column in chars
+/// and the byte offset. "\r\n" is read as a single '\n'.
+struct Lexer<'a> {
+    source: &'a str,
+    chars: Peekable<CharIndices<'a>>,
+    line: usize,
+    column: usize,
+}
+
+impl<'a> Lexer<'a> {
+    fn new(source: &'a str) -> Self {
+        Lexer {
+            source,
+            chars: source.char_indices().peekable(),
+            line: 0,
+            column: 0,
+        }
+    }
+
+    fn offset(&mut self) -> usize {
+        self.chars.peek().map_or(self.source.len(), |&(i, _)| i)
+    }
+
+    /// The source from the next char on
+    fn rest(&mut self) -> &'a str {
+        let offset = self.offset();
+        &self.source[offset..]
+    }
+
+    /// The position of the next char, with a length of 0
+    fn context(&mut self) -> TokenContext {
+        TokenContext {
+            line_number: self.line,
+            column_number: self.column,
+            offset: self.offset(),
+            length: 0,
+        }
+    }
+
+    fn peek(&mut self) -> Option<char> {
+        self.chars.peek().map(|&(_, c)| c)
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let (_, mut c) = self.chars.next()?;
+        if c == '\r' && self.peek() == Some('\n') {
+            self.chars.next();
+            c = '\n';
+        }
+        if c == '\n' {
+            self.line += 1;
+            self.column = 0;
+        } else {
+            self.column += 1;
+        }
+        Some(c)
+    }
+
+    fn next_if(&mut self, predicate: impl FnOnce(char) -> bool) -> Option<char> {
+        match self.peek() {
+            Some(c) if predicate(c) => self.next(),
+            _ => None,
+        }
+    }
+
+    /// Skips ahead to the byte `offset`
+    fn advance_to(&mut self, offset: usize) {
+        while self.offset() < offset && self.next().is_some() {}
+    }
+}
+
+/// An error for the source from `start` up to and including `end`. Errors span a single line,
+/// so if `end` lies on a later line only `end` itself is reported.
+fn error_at(start: &TokenContext, end: &TokenContext, message: &str) -> TokenizingError {
+    let start = if start.line_number == end.line_number {
+        start
+    } else {
+        end
+    };
+    TokenizingError::new(
+        start.line_number,
+        start.column_number,
+        end.column_number,
+        message,
+    )
+}
+
+/// Underlines the string or |quoted symbol| from its start up to the end of that line
+fn unclosed_string(source: &str, start: &TokenContext) -> TokenizingError {
+    let line = source[start.offset..].lines().next().unwrap_or_default();
+    let end = start.column_number + line.chars().count().saturating_sub(1);
+    let message = if line.starts_with('|') {
+        "Unclosed symbol, expected '|'"
+    } else {
+        "Unclosed String"
+    };
+    TokenizingError {
+        unclosed: true,
+        ..TokenizingError::new(start.line_number, start.column_number, end, message)
+    }
+}
+
+/// Parses a string literal, or a |quoted symbol|, after its opening delimiter at `start`.
+/// Both can span lines and use the same escapes.
+fn parse_string(lexer: &mut Lexer, start: &TokenContext) -> Result<String, TokenizingError> {
+    let delimiter = lexer.source[start.offset..].chars().next();
     let mut str = String::new();
-    let mut string_closed = false;
-    let mut last_index = 0;
+    loop {
+        let escape_start = lexer.context();
+        match lexer.next() {
+            c if c == delimiter => return Ok(str),
+            Some('\\') => {
+                if let Some(escaped) = parse_escape(lexer, start, &escape_start)? {
+                    str.push(escaped);
+                }
+            }
+            Some(c) => str.push(c),
+            None => return Err(unclosed_string(lexer.source, start)),
+        }
+    }
+}
 
-    for (i, c) in input {
-        if c == '"' {
-            string_closed = true;
-            break;
+/// Parses the escape sequence after the backslash at `backslash`. A backslash at the end of
+/// a line continues the string on the next line without the leading whitespace.
+fn parse_escape(
+    lexer: &mut Lexer,
+    start: &TokenContext,
+    backslash: &TokenContext,
+) -> Result<Option<char>, TokenizingError> {
+    let escaped_at = lexer.context();
+    let escaped = match lexer.next() {
+        Some('n') => '\n',
+        Some('t') => '\t',
+        Some('r') => '\r',
+        Some('0') => '\0',
+        Some('\\') => '\\',
+        Some('"') => '"',
+        Some('\'') => '\'',
+        Some('|') => '|',
+        Some('u') => parse_unicode_escape(lexer, backslash)?,
+        Some('\n') => {
+            while lexer.next_if(char::is_whitespace).is_some() {}
+            return Ok(None);
+        }
+        Some(c) => {
+            return Err(error_at(
+                backslash,
+                &escaped_at,
+                &format!("Unknown escape sequence '\\{}'", c),
+            ))
         }
-        str.push(c);
-        last_index = i;
+        None => return Err(unclosed_string(lexer.source, start)),
+    };
+    Ok(Some(escaped))
+}
+
+/// Parses the `{...}` part of a `\u{...}` escape, holding 1 to 6 hexadecimal digits
+fn parse_unicode_escape(
+    lexer: &mut Lexer,
+    backslash: &TokenContext,
+) -> Result<char, TokenizingError> {
+    let malformed = |end: &TokenContext| {
+        error_at(
+            backslash,
+            end,
+            "Invalid unicode escape, expected 1 to 6 hexadecimal digits like '\\u{1F600}'",
+        )
+    };
+
+    if lexer.next_if(|c| c == '{').is_none() {
+        return Err(malformed(&lexer.context()));
     }
-    if !string_closed {
-        return Err(TokenizingError::new(
-            context.line_number,
-            context.column_number,
-            last_index,
-            "Unclosed String",
-        ));
+    let mut digits = String::new();
+    loop {
+        let at = lexer.context();
+        match lexer.next() {
+            Some('}') if !digits.is_empty() => {
+                let code =
+                    u32::from_str_radix(&digits, 16).expect("only hex digits were collected");
+                return char::from_u32(code).ok_or_else(|| {
+                    error_at(
+                        backslash,
+                        &at,
+                        &format!("'\\u{{{}}}' is not a valid unicode character", digits),
+                    )
+                });
+            }
+            Some(c) if c.is_ascii_hexdigit() && digits.len() < 6 => digits.push(c),
+            _ => return Err(malformed(&at)),
+        }
     }
+}
 
-    let content = TokenContent::String(str);
-    Ok(content)
+/// Whether `rest`, the source after an 'r', opens a raw string. Otherwise the 'r' starts a
+/// symbol, e.g. r#x.
+fn is_raw_string_start(rest: &str) -> bool {
+    rest.trim_start_matches('#').starts_with('"')
+}
+
+/// Parses a raw string after its leading 'r' at `start`. The contents are taken verbatim,
+/// `r#"..."#` can contain quotes, with as many '#' as needed on both ends.
+fn parse_raw_string(
+    lexer: &mut Lexer,
+    start: &TokenContext,
+) -> Result<TokenContent, TokenizingError> {
+    let mut hashes = 0;
+    while lexer.next_if(|c| c == '#').is_some() {
+        hashes += 1;
+    }
+    // The opening quote, checked by is_raw_string_start
+    lexer.next();
+    let terminator = format!("\"{}", "#".repeat(hashes));
+    let contents = lexer.rest();
+    let Some(len) = contents.find(&terminator) else {
+        return Err(unclosed_string(lexer.source, start));
+    };
+    let end = lexer.offset() + len + terminator.len();
+    lexer.advance_to(end);
+    Ok(TokenContent::String(contents[..len].replace("\r\n", "\n")))
+}
+
+/// Skips a block comment after its opening '#|' at `start`, block comments can be nested
+fn skip_block_comment(lexer: &mut Lexer, start: &TokenContext) -> Result<(), TokenizingError> {
+    let mut depth = 1;
+    while depth > 0 {
+        match lexer.next() {
+            Some('|') if lexer.next_if(|c| c == '#').is_some() => depth -= 1,
+            Some('#') if lexer.next_if(|c| c == '|').is_some() => depth += 1,
+            Some(_) => {}
+            None => {
+                return Err(TokenizingError {
+                    unclosed: true,
+                    ..TokenizingError::new(
+                        start.line_number,
+                        start.column_number,
+                        start.column_number + 1,
+                        "Unclosed block comment, expected '|#'",
+                    )
+                })
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Symbols are made of anything but whitespace and the chars with a meaning of their own, so
+/// e.g. null?, set!, string->symbol, *global*, <=> and my_var are all symbols
+fn is_symbol_char(c: char) -> bool {
+    !c.is_whitespace() && !c.is_control() && !matches!(c, '(' | ')' | '"' | '\'' | ';' | '|')
+}
+
+/// Whether `name` reads back as the symbol `name` without being written as |name|
+pub fn is_plain_symbol(name: &str) -> bool {
+    match tokenize(name).as_deref() {
+        Ok([token]) => match (&token.token_type, &token.content) {
+            (TokenType::Identifier, Some(TokenContent::String(s))) => {
+                // A lone . is the dot of a dotted pair, e.g. '(1 . 2)
+                s == name && !matches!(name, "true" | "false" | "null" | ".")
+            }
+            (TokenType::Operator, _) => true,
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+/// Whether `source` is ready to be evaluated, i.e. it does not end inside a string, a |symbol|,
+/// a block comment or an open parenthesis. Input that fails to tokenize for any other reason
+/// is complete, more of it would not fix the error.
+pub fn is_complete(source: &str) -> bool {
+    match tokenize(source) {
+        Ok(tokens) => {
+            let depth = tokens
+                .iter()
+                .fold(0i64, |depth, token| match token.token_type {
+                    Lparen => depth + 1,
+                    Rparen => depth - 1,
+                    _ => depth,
+                });
+            depth <= 0
+        }
+        Err(err) => !err.unclosed,
+    }
 }
 
-pub fn tokenize_line(
-    input_str: &str,
-    tokens: &mut Vec<Token>,
-    line_num: usize,
-) -> Result<(), TokenizingError> {
-    let mut input = input_str.char_indices().peekable();
+pub fn tokenize(source: &str) -> Result<Vec<Token>, TokenizingError> {
+    let mut tokens: Vec<Token> = Vec::new();
+    let mut lexer = Lexer::new(source);
 
-    while let Some((index, c)) = input.next() {
+    loop {
+        let context = lexer.context();
+        let Some(c) = lexer.next() else {
+            break;
+        };
         if c.is_whitespace() {
             continue;
         }
-        let context = TokenContext {
-            line_number: line_num,
-            column_number: index,
-        };
-        if c.is_numeric() {
-            let content = parse_number(input.by_ref(), c, &context)?;
-            tokens.push(Token::new(Number, context, Some(content)));
+        let unrecognized =
+            |c: char| error_at(&context, &context, &format!("Unrecognized Token '{}'", c));
+        if starts_number(&source[context.offset..]) {
+            let len = number_len(&source[context.offset..]);
+            let content = parse_number(&source[context.offset..context.offset + len], &context)?;
+            lexer.advance_to(context.offset + len);
+            let token = Token::new(Number, context, Some(content));
+            tokens.push(token_with_length(token, &mut lexer));
             continue;
         }
 
         let token = match c {
             '(' => Token::new(Lparen, context, None),
             ')' => Token::new(Rparen, context, None),
-            '+' => Token::new(Operator, context, Some(TokenContent::Operator(Plus))),
-            '-' => {
-                let mut is_unary_minus = false;
-                if let Some((_, next_c)) = input.peek() {
-                    if next_c.is_numeric() {
-                        is_unary_minus = true;
-                    }
-                };
-
-                if is_unary_minus {
-                    let content = parse_number(input.by_ref(), c, &context)?;
-                    Token::new(TokenType::Number, context, Some(content))
-                } else {
-                    Token::new(Operator, context, Some(TokenContent::Operator(Minus)))
-                }
+            '"' => {
+                let content = Some(TokenContent::String(parse_string(&mut lexer, &context)?));
+                Token::new(TokenType::String, context, content)
             }
-            '*' => Token::new(Operator, context, Some(TokenContent::Operator(Mul))),
-            '%' => Token::new(Operator, context, Some(TokenContent::Operator(Modulo))),
-            '^' => Token::new(Operator, context, Some(TokenContent::Operator(Power))),
-            '>' => {
-                let content = if let Some((_, '=')) = input.peek() {
-                    _ = input.next();
-                    TokenContent::Operator(Geq)
-                } else {
-                    TokenContent::Operator(Gt)
-                };
-                Token::new(Operator, context, Some(content))
+            '|' => {
+                let content = Some(TokenContent::String(parse_string(&mut lexer, &context)?));
+                Token::new(TokenType::QuotedIdentifier, context, content)
             }
-            '<' => {
-                let content = if let Some((_, '=')) = input.peek() {
-                    _ = input.next();
-                    TokenContent::Operator(Leq)
-                } else {
-                    TokenContent::Operator(Lt)
-                };
-                Token::new(Operator, context, Some(content))
+            'r' if is_raw_string_start(lexer.rest()) => {
+                let content = Some(parse_raw_string(&mut lexer, &context)?);
+                Token::new(TokenType::String, context, content)
             }
-            '=' => Token::new(Operator, context, Some(TokenContent::Operator(Eq))),
-
-            '!' => {
-                let content = if let Some((_, '=')) = input.peek() {
-                    let _ = input.next();
-                    TokenContent::Operator(Neq)
-                } else {
-                    return Err(TokenizingError::new(
-                        line_num,
-                        index,
-                        index,
-                        std::format!("Unrecognized Token '{c}'").as_str(),
-                    ));
-                };
-                Token::new(Operator, context, Some(content))
+            '\'' => Token::new(TokenType::Quote, context, None),
+            ';' => {
+                while lexer.next_if(|c| c != '\n').is_some() {}
+                continue;
             }
-            '/' => {
-                let content = if let Some((_, '/')) = input.peek() {
-                    let _ = input.next();
-                    TokenContent::Operator(IntDiv)
-                } else {
-                    TokenContent::Operator(Div)
-                };
-
-                Token::new(Operator, context, Some(content))
+            '#' if lexer.next_if(|c| c == '|').is_some() => {
+                skip_block_comment(&mut lexer, &context)?;
+                continue;
             }
-
-            '"' => {
-                let content = Some(parse_string(input.by_ref(), &context)?);
-                Token::new(TokenType::String, context, content)
+            '#' if lexer.next_if(|c| c == ';').is_some() => {
+                Token::new(TokenType::DatumComment, context, None)
             }
-            '\'' => Token::new(TokenType::Quote, context, None),
-            _ => {
-                if c.is_alphabetic() {
-                    let mut identifier = String::from(c);
-                    while let Some(&(_, c)) = input.peek() {
-                        if c.is_alphanumeric() || c == '-' {
-                            identifier.push(c);
-                            input.next();
-                        } else {
-                            break;
-                        }
-                    }
-                    Token::new(
+            c if is_symbol_char(c) && c != '#' => {
+                let mut symbol = String::from(c);
+                while let Some(c) = lexer.next_if(is_symbol_char) {
+                    symbol.push(c);
+                }
+                // Operators are symbols too, the ones the evaluator implements natively get
+                // their own token
+                match operatortype::Operator::from_symbol(&symbol) {
+                    Some(op) => Token::new(Operator, context, Some(TokenContent::Operator(op))),
+                    None => Token::new(
                         TokenType::Identifier,
                         context,
-                        Some(TokenContent::String(identifier)),
-                    )
-                } else {
-                    return Err(TokenizingError::new(
-                        line_num,
-                        index,
-                        index,
-                        std::format!("Unrecognized Token '{}'", c).as_str(),
-                    ));
+                        Some(TokenContent::String(symbol)),
+                    ),
                 }
             }
+            c => return Err(unrecognized(c)),
         };
-        tokens.push(token);
+        tokens.push(token_with_length(token, &mut lexer));
     }
 
-    Ok(())
+    Ok(tokens)
 }
 
-pub fn tokenize(input: &str) -> Result<Vec<Token>, TokenizingError> {
-    let mut tokens: Vec<Token> = Vec::new();
-
-    for (line_number, line) in input.lines().enumerate() {
-        tokenize_line(line, &mut tokens, line_number)?
-    }
-
-    Ok(tokens)
+/// Sets the length of `token`, which ends right before the next char of `lexer`
+fn token_with_length(mut token: Token, lexer: &mut Lexer) -> Token {
+    let end = lexer.offset();
+    token.context.length = lexer.source[token.context.offset..end].chars().count();
+    token
 }
@@ -0,0 +1,40 @@
+use std::cell::Cell;
+use std::fmt::Display;
+
+use crate::errors::RuntimeError;
+use crate::value::Value;
+
+thread_local! {
+    // Current nesting depth of traced evaluation steps, None when tracing is off
+    static DEPTH: Cell<Option<usize>> = const { Cell::new(None) };
+}
+
+pub fn set_enabled(enabled: bool) {
+    DEPTH.with(|depth| depth.set(if enabled { Some(0) } else { None }));
+}
+
+pub fn is_enabled() -> bool {
+    DEPTH.with(|depth| depth.get().is_some())
+}
+
+pub fn enter(expr: &dyn Display) {
+    DEPTH.with(|depth| {
+        if let Some(d) = depth.get() {
+            eprintln!("{}{}", "  ".repeat(d), expr);
+            depth.set(Some(d + 1));
+        }
+    });
+}
+
+pub fn exit(result: &Result<Value, RuntimeError>) {
+    DEPTH.with(|depth| {
+        if let Some(d) = depth.get() {
+            let d = d.saturating_sub(1);
+            match result {
+                Ok(value) => eprintln!("{}=> {}", "  ".repeat(d), value),
+                Err(err) => eprintln!("{}!! {}", "  ".repeat(d), err.message),
+            }
+            depth.set(Some(d));
+        }
+    });
+}
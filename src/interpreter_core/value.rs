@@ -1,5 +1,21 @@
-use crate::errors::RuntimeError;
-use std::ops::{Add, Mul};
+use crate::ast::AstNode;
+use crate::builtin::BuiltinFunction;
+use crate::env::Environment;
+use crate::errors::{RuntimeError, RuntimeErrorKind};
+use crate::eval::{STACK_RED_ZONE, STACK_SEGMENT_SIZE};
+use crate::symbol::Symbol;
+use crate::tokenize::is_plain_symbol;
+use num_bigint::BigInt;
+use num_integer::Integer;
+use num_rational::BigRational;
+use num_traits::{One, Pow, Signed, ToPrimitive, Zero};
+use std::cell::Cell;
+use std::cmp::Ordering;
+use std::ops::{Add, Mul, Sub};
+use std::rc::Rc;
+
+/// Exponentiation results beyond this many bits are rejected instead of exhausting memory.
+const MAX_POW_BITS: u64 = 1 << 24;
 
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum NumBase {
@@ -9,28 +25,165 @@ pub enum NumBase {
     Bin,
 }
 impl NumBase {
+    /// The base selected by the letter after a leading 0, e.g. the x in 0xFF.
+    pub fn from_prefix(prefix: char) -> Option<NumBase> {
+        match prefix {
+            'x' | 'X' => Some(NumBase::Hex),
+            'o' | 'O' => Some(NumBase::Oct),
+            'b' | 'B' => Some(NumBase::Bin),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            NumBase::Dec => "decimal",
+            NumBase::Hex => "hexadecimal",
+            NumBase::Oct => "octal",
+            NumBase::Bin => "binary",
+        }
+    }
+
     pub fn is_valid_digit(self, digit: char) -> bool {
+        digit.is_digit(self.radix())
+    }
+
+    /// The parse functions expect plain digits, without a sign, prefix or separators.
+    pub fn parse_int(self, digits: &str) -> Result<i64, std::num::ParseIntError> {
+        i64::from_str_radix(digits, self.radix())
+    }
+
+    pub fn parse_bigint(self, digits: &str) -> Option<BigInt> {
+        BigInt::parse_bytes(digits.as_bytes(), self.radix())
+    }
+
+    pub fn radix(self) -> u32 {
         match self {
-            NumBase::Dec => digit.is_numeric(),
-            NumBase::Bin => digit == '0' || digit == '1',
-            NumBase::Oct => ('0'..'7').contains(&digit),
-            NumBase::Hex => digit.is_ascii_hexdigit(),
+            NumBase::Dec => 10,
+            NumBase::Bin => 2,
+            NumBase::Oct => 8,
+            NumBase::Hex => 16,
         }
     }
 
-    pub fn parse_int(self, num_str: &str) -> Result<i64, std::num::ParseIntError> {
+    pub fn parse_float(self, digits: &str) -> Option<f64> {
+        if self == NumBase::Dec {
+            return digits.parse().ok();
+        }
+
+        // Digits are accumulated by hand, as the standard library only parses decimal floats
+        let radix = self.radix();
+        let (int_part, frac_part) = digits.split_once('.').unwrap_or((digits, ""));
+        let mut value = 0.0;
+        for c in int_part.chars() {
+            value = value * radix as f64 + c.to_digit(radix)? as f64;
+        }
+        let mut scale = 1.0 / radix as f64;
+        for c in frac_part.chars() {
+            value += c.to_digit(radix)? as f64 * scale;
+            scale /= radix as f64;
+        }
+        Some(value)
+    }
+}
+
+thread_local! {
+    static COLLAPSE_FLOATS: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Restores the old behavior of turning whole float results like (+ 2.5 2.5) into integers
+/// on the current thread.
+pub fn set_collapse_floats(enabled: bool) {
+    COLLAPSE_FLOATS.with(|collapse| collapse.set(enabled));
+}
+
+/// The operations shared by every level of the numeric tower.
+#[derive(Clone, Copy)]
+enum Arithmetic {
+    Add,
+    Sub,
+    Mul,
+}
+
+impl Arithmetic {
+    fn name(self) -> &'static str {
+        match self {
+            Arithmetic::Add => "addition",
+            Arithmetic::Sub => "subtraction",
+            Arithmetic::Mul => "multiplication",
+        }
+    }
+
+    fn checked_int(self, l: i64, r: i64) -> Option<i64> {
         match self {
-            NumBase::Dec => num_str.parse(),
-            NumBase::Bin => i64::from_str_radix(num_str.trim_start_matches("0b"), 2),
-            NumBase::Oct => i64::from_str_radix(num_str.trim_start_matches("0o"), 8),
-            NumBase::Hex => i64::from_str_radix(num_str.trim_start_matches("0x"), 16),
+            Arithmetic::Add => l.checked_add(r),
+            Arithmetic::Sub => l.checked_sub(r),
+            Arithmetic::Mul => l.checked_mul(r),
         }
     }
 
-    pub fn parse_float(self, num_str: &str) -> Result<f64, std::num::ParseFloatError> {
+    fn apply<T>(self, l: T, r: T) -> T
+    where
+        T: Add<Output = T> + Sub<Output = T> + Mul<Output = T>,
+    {
         match self {
-            NumBase::Dec => num_str.parse(),
-            _ => unimplemented!(),
+            Arithmetic::Add => l + r,
+            Arithmetic::Sub => l - r,
+            Arithmetic::Mul => l * r,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Closure {
+    pub name: Option<String>,
+    pub params: Vec<String>,
+    pub body: Vec<AstNode>,
+    pub env: Environment,
+}
+
+/// A cons cell. Lists are chains of pairs ending in the empty list, sharing their tails
+#[derive(Debug)]
+pub struct Pair {
+    pub car: Value,
+    pub cdr: Value,
+}
+
+impl Drop for Pair {
+    // Dropping a long or deeply nested list recursively would overflow the stack, so the
+    // pairs only owned by this one are unlinked through a work list instead
+    fn drop(&mut self) {
+        if !matches!(self.car, Value::Pair(_)) && !matches!(self.cdr, Value::Pair(_)) {
+            return;
+        }
+        let mut pending = vec![
+            std::mem::replace(&mut self.car, Value::Nil),
+            std::mem::replace(&mut self.cdr, Value::Nil),
+        ];
+        while let Some(value) = pending.pop() {
+            if let Value::Pair(pair) = value {
+                if let Ok(mut pair) = Rc::try_unwrap(pair) {
+                    pending.push(std::mem::replace(&mut pair.car, Value::Nil));
+                    pending.push(std::mem::replace(&mut pair.cdr, Value::Nil));
+                }
+            }
+        }
+    }
+}
+
+/// Iterates over the elements of a list, stopping at the first cdr that is not a pair
+pub struct ListIter<'a>(&'a Value);
+
+impl<'a> Iterator for ListIter<'a> {
+    type Item = &'a Value;
+
+    fn next(&mut self) -> Option<&'a Value> {
+        match self.0 {
+            Value::Pair(pair) => {
+                self.0 = &pair.cdr;
+                Some(&pair.car)
+            }
+            _ => None,
         }
     }
 }
@@ -38,14 +191,55 @@ impl NumBase {
 #[derive(Debug, Clone)]
 pub enum Value {
     Int(i64),
+    /// Only holds integers outside of the i64 range, smaller ones are always an Int
+    BigInt(BigInt),
+    /// Only holds fractions whose denominator is not 1, whole numbers are always an integer
+    Rational(Rc<BigRational>),
     Float(f64),
     Boolean(bool),
-    String(String),
-    _List(Vec<Value>),
+    /// Strings are immutable, so copies of a value share the text, which eq? compares by identity
+    String(Rc<str>),
+    Symbol(Symbol),
+    /// The empty list
+    Nil,
+    Pair(Rc<Pair>),
+    Closure(Rc<Closure>),
+    Builtin(&'static BuiltinFunction),
+    /// An error caught by try, it can be inspected or raised again
+    Error(Rc<RuntimeError>),
     None,
 }
 
 impl Value {
+    pub fn cons(car: Value, cdr: Value) -> Value {
+        Value::Pair(Rc::new(Pair { car, cdr }))
+    }
+
+    /// Builds a proper list of `items`
+    pub fn list<I>(items: I) -> Value
+    where
+        I: IntoIterator<Item = Value>,
+        I::IntoIter: DoubleEndedIterator,
+    {
+        items
+            .into_iter()
+            .rev()
+            .fold(Value::Nil, |tail, item| Value::cons(item, tail))
+    }
+
+    pub fn iter(&self) -> ListIter<'_> {
+        ListIter(self)
+    }
+
+    /// Whether the value is a proper list, i.e. the empty list or pairs ending in it
+    pub fn is_list(&self) -> bool {
+        let mut rest = self;
+        while let Value::Pair(pair) = rest {
+            rest = &pair.cdr;
+        }
+        matches!(rest, Value::Nil)
+    }
+
     #[allow(dead_code)]
     pub fn promote_to_float(self) -> Self {
         match self {
@@ -54,237 +248,395 @@ impl Value {
         }
     }
 
+    /// Only `false` and `null` are falsy, every other value (including 0 and "") is truthy.
+    pub fn is_truthy(&self) -> bool {
+        !matches!(self, Value::Boolean(false) | Value::None)
+    }
+
     pub fn is_numeric(&self) -> bool {
-        matches!(self, Value::Int(_) | Value::Float(_))
+        matches!(
+            self,
+            Value::Int(_) | Value::BigInt(_) | Value::Rational(_) | Value::Float(_)
+        )
     }
 
     pub fn get_numeric_value_as_float(&self) -> Option<f64> {
-        match *self {
-            Value::Int(v) => Some(v as f64),
-            Value::Float(v) => Some(v),
+        match self {
+            Value::Int(v) => Some(*v as f64),
+            Value::BigInt(v) => v.to_f64(),
+            Value::Rational(v) => v.to_f64(),
+            Value::Float(v) => Some(*v),
             _ => None,
         }
     }
 
-    pub fn int_div_assign(&mut self, rhs: Value) {
-        let result: f64 = match (&*self, rhs) {
-            (Value::Int(l), Value::Int(r)) => *l as f64 / r as f64,
-            (Value::Float(l), Value::Float(r)) => *l / r,
-            (Value::Int(l), Value::Float(r)) => (*l as f64) / r,
-            (Value::Float(l), Value::Int(r)) => *l / (r as f64),
-            _ => unreachable!(),
-        };
-        *self = Value::Int(result as i64);
+    /// Demotes `value` to an Int if it fits.
+    pub fn from_bigint(value: BigInt) -> Value {
+        match value.to_i64() {
+            Some(v) => Value::Int(v),
+            None => Value::BigInt(value),
+        }
     }
 
-    pub fn pow_assign(&mut self, rhs: Self) {
-        if !(self.is_numeric() && rhs.is_numeric()) {}
+    pub fn to_bigint(&self) -> Option<BigInt> {
+        match self {
+            Value::Int(v) => Some(BigInt::from(*v)),
+            Value::BigInt(v) => Some(v.clone()),
+            _ => None,
+        }
+    }
 
-        let result: f64 = match (&mut *self, rhs) {
-            (Value::Int(l), Value::Int(r)) => (*l as f64).powf(r as f64),
-            (Value::Float(l), Value::Float(r)) => l.powf(r),
-            (Value::Int(l), Value::Float(r)) => (*l as f64).powf(r),
-            (Value::Float(l), Value::Int(r)) => l.powf(r as f64),
-            _ => unreachable!(),
-        };
+    fn integer_operands(&self, rhs: &Value) -> Option<(BigInt, BigInt)> {
+        Some((self.to_bigint()?, rhs.to_bigint()?))
+    }
 
-        if result.fract() == 0.0 {
-            *self = Value::Int(result as i64);
+    /// Demotes `value` to an integer if its denominator is 1.
+    pub fn from_rational(value: BigRational) -> Value {
+        if value.is_integer() {
+            Value::from_bigint(value.to_integer())
         } else {
-            *self = Value::Float(result);
+            Value::Rational(Rc::new(value))
         }
     }
 
-    pub fn negate(&mut self) {
+    /// Returns exact numbers as a rational.
+    pub fn to_rational(&self) -> Option<BigRational> {
         match self {
-            Value::Boolean(v) => *v = !(*v),
-            Value::Float(v) => *v *= -1.0,
-            Value::Int(v) => *v *= -1,
-            _ => unreachable!(),
+            Value::Int(_) | Value::BigInt(_) => Some(BigRational::from_integer(self.to_bigint()?)),
+            Value::Rational(v) => Some(BigRational::clone(v)),
+            _ => None,
         }
     }
 
-    pub fn compare_to(&self, rhs: &Self) -> Result<i64, RuntimeError> {
-        if !(self.is_numeric() && rhs.is_numeric()) {
-            let msg = format!(
-                "Incompatible Types for comparision: {:?} and {:?}",
-                self, rhs
-            );
-            return Err(RuntimeError::new(msg));
-        }
+    fn rational_operands(&self, rhs: &Value) -> Option<(BigRational, BigRational)> {
+        Some((self.to_rational()?, rhs.to_rational()?))
+    }
 
-        let l = self.get_numeric_value_as_float().unwrap();
-        let r = rhs.get_numeric_value_as_float().unwrap();
-        let cmp = l - r;
-        if cmp < 0.0 {
-            return Ok(-1);
-        } else if cmp > 0.0 {
-            return Ok(1);
-        }
-        Ok(0)
+    fn type_error(op: &str, lhs: &Value, rhs: &Value) -> RuntimeError {
+        RuntimeError::type_mismatch(format!(
+            "Incompatible Types for {}: {} and {}",
+            op, lhs, rhs
+        ))
     }
-}
 
-impl Add for Value {
-    type Output = Value;
+    fn division_by_zero(lhs: &Value) -> RuntimeError {
+        RuntimeError::new(
+            RuntimeErrorKind::DivisionByZero,
+            format!("Attempted to divide {} by zero", lhs),
+        )
+    }
 
-    fn add(self, rhs: Value) -> Value {
-        match (self, rhs) {
-            (Value::Int(l), Value::Int(r)) => Value::Int(l + r),
-            (Value::Float(l), Value::Float(r)) => Value::Float(l + r),
-            (Value::Int(l), Value::Float(r)) => Value::Float(l as f64 + r),
-            (Value::Float(l), Value::Int(r)) => Value::Float(l + r as f64),
-            (Value::String(l), Value::String(r)) => Value::String(l + &r),
-            _ => Value::None,
+    /// Wraps the result of float arithmetic. It stays a float unless the compatibility mode
+    /// turning whole results back into integers is enabled.
+    fn float_result(result: f64) -> Value {
+        let collapse = COLLAPSE_FLOATS.with(|collapse| collapse.get());
+        if collapse
+            && result.fract() == 0.0
+            && result >= i64::MIN as f64
+            && result < i64::MAX as f64
+        {
+            Value::Int(result as i64)
+        } else {
+            Value::Float(result)
         }
     }
-}
 
-impl std::ops::AddAssign for Value {
-    fn add_assign(&mut self, rhs: Value) {
-        if let Value::Int(l) = self {
-            if let Value::Int(r) = rhs {
-                *l += r;
-                return;
+    fn float_operands(&self, rhs: &Value) -> Option<(f64, f64)> {
+        Some((
+            self.get_numeric_value_as_float()?,
+            rhs.get_numeric_value_as_float()?,
+        ))
+    }
+
+    fn arithmetic(self, rhs: Value, op: Arithmetic) -> Result<Value, RuntimeError> {
+        if let (Value::Int(l), Value::Int(r)) = (&self, &rhs) {
+            if let Some(result) = op.checked_int(*l, *r) {
+                return Ok(Value::Int(result));
             }
         }
-
-        let result: f64 = match (&*self, rhs) {
-            (Value::Float(l), Value::Float(r)) => *l + r,
-            (Value::Int(l), Value::Float(r)) => (*l as f64) + r,
-            (Value::Float(l), Value::Int(r)) => *l + (r as f64),
-            _ => unreachable!(),
-        };
-
-        if result.fract() == 0.0 {
-            *self = Value::Int(result as i64);
-        } else {
-            *self = Value::Float(result);
+        // Integer results that overflow an i64 are promoted to a bignum
+        if let Some((l, r)) = self.integer_operands(&rhs) {
+            return Ok(Value::from_bigint(op.apply(l, r)));
+        }
+        if let Some((l, r)) = self.rational_operands(&rhs) {
+            return Ok(Value::from_rational(op.apply(l, r)));
+        }
+        match self.float_operands(&rhs) {
+            Some((l, r)) => Ok(Value::float_result(op.apply(l, r))),
+            None => Err(Value::type_error(op.name(), &self, &rhs)),
         }
     }
-}
 
-impl std::ops::SubAssign for Value {
-    fn sub_assign(&mut self, rhs: Value) {
-        if let Value::Int(l) = self {
-            if let Value::Int(r) = rhs {
-                *l -= r;
-                return;
-            }
+    pub fn checked_add(self, rhs: Value) -> Result<Value, RuntimeError> {
+        if let (Value::String(l), Value::String(r)) = (&self, &rhs) {
+            return Ok(Value::String(format!("{}{}", l, r).into()));
         }
+        self.arithmetic(rhs, Arithmetic::Add)
+    }
 
-        let result: f64 = match (&*self, rhs) {
-            (Value::Float(l), Value::Float(r)) => *l - r,
-            (Value::Int(l), Value::Float(r)) => (*l as f64) - r,
-            (Value::Float(l), Value::Int(r)) => *l - (r as f64),
-            _ => unreachable!(),
-        };
+    pub fn checked_sub(self, rhs: Value) -> Result<Value, RuntimeError> {
+        self.arithmetic(rhs, Arithmetic::Sub)
+    }
 
-        if result.fract() == 0.0 {
-            *self = Value::Int(result as i64);
-        } else {
-            *self = Value::Float(result);
-        }
+    pub fn checked_mul(self, rhs: Value) -> Result<Value, RuntimeError> {
+        self.arithmetic(rhs, Arithmetic::Mul)
     }
-}
 
-impl std::ops::MulAssign for Value {
-    fn mul_assign(&mut self, rhs: Value) {
-        if let Value::Int(l) = self {
-            if let Value::Int(r) = rhs {
-                *l *= r;
-                return;
+    /// Division of exact numbers stays exact, e.g. (/ 1 3) is the rational 1/3.
+    pub fn checked_div(self, rhs: Value) -> Result<Value, RuntimeError> {
+        if let Value::Int(0) = rhs {
+            return Err(Value::division_by_zero(&self));
+        }
+        if let (Value::Int(l), Value::Int(r)) = (&self, &rhs) {
+            if l.checked_rem(*r) == Some(0) {
+                return Ok(Value::Int(l / r));
             }
         }
+        if let Some((l, r)) = self.rational_operands(&rhs) {
+            return Ok(Value::from_rational(l / r));
+        }
+        match self.float_operands(&rhs) {
+            Some((l, r)) => Ok(Value::float_result(l / r)),
+            None => Err(Value::type_error("division", &self, &rhs)),
+        }
+    }
 
-        let result: f64 = match (&*self, rhs) {
-            (Value::Float(l), Value::Float(r)) => *l * r,
-            (Value::Int(l), Value::Float(r)) => (*l as f64) * r,
-            (Value::Float(l), Value::Int(r)) => *l * (r as f64),
-            _ => unreachable!(),
-        };
+    pub fn checked_int_div(self, rhs: Value) -> Result<Value, RuntimeError> {
+        if let Value::Int(0) = rhs {
+            return Err(Value::division_by_zero(&self));
+        }
+        if let (Value::Int(l), Value::Int(r)) = (&self, &rhs) {
+            if let Some(result) = l.checked_div(*r) {
+                return Ok(Value::Int(result));
+            }
+        }
+        if let Some((l, r)) = self.integer_operands(&rhs) {
+            return Ok(Value::from_bigint(l / r));
+        }
+        if let Some((l, r)) = self.rational_operands(&rhs) {
+            return Ok(Value::from_bigint((l / r).trunc().to_integer()));
+        }
+        // Like any other float arithmetic the result stays inexact, e.g. (// 7.5 2) is 3.0
+        match self.float_operands(&rhs) {
+            Some((l, r)) => Ok(Value::float_result((l / r).trunc())),
+            None => Err(Value::type_error("integer division", &self, &rhs)),
+        }
+    }
 
-        if result.fract() == 0.0 {
-            *self = Value::Int(result as i64);
-        } else {
-            *self = Value::Float(result);
+    pub fn checked_rem(self, rhs: Value) -> Result<Value, RuntimeError> {
+        if let Value::Int(0) = rhs {
+            return Err(Value::division_by_zero(&self));
+        }
+        if let (Value::Int(l), Value::Int(r)) = (&self, &rhs) {
+            // The result takes the sign of the divisor, e.g. (% -2 24) is 22
+            let rem = l.wrapping_rem(*r);
+            if rem != 0 && (rem < 0) != (*r < 0) {
+                return Ok(Value::Int(rem + r));
+            }
+            return Ok(Value::Int(rem));
+        }
+        if let Some((l, r)) = self.integer_operands(&rhs) {
+            return Ok(Value::from_bigint(l.mod_floor(&r)));
+        }
+        if let Some((l, r)) = self.rational_operands(&rhs) {
+            let quotient = (&l / &r).floor();
+            return Ok(Value::from_rational(l - r * quotient));
+        }
+        match self.float_operands(&rhs) {
+            Some((l, r)) => Ok(Value::Float(((l % r) + r) % r)),
+            None => Err(Value::type_error("modulo", &self, &rhs)),
         }
     }
-}
 
-impl std::ops::RemAssign for Value {
-    fn rem_assign(&mut self, rhs: Self) {
-        let result: f64 = match (&mut *self, rhs) {
-            (Value::Int(l), Value::Int(r)) => {
-                *l = ((*l % r) + r) % r;
-                return;
+    pub fn checked_pow(self, rhs: Value) -> Result<Value, RuntimeError> {
+        // Exact numbers raised to an integer power stay exact, e.g. (^ 2 -2) is 1/4
+        if let (Some(base), Some(exp)) = (self.to_rational(), rhs.to_bigint()) {
+            if exp.is_negative() && base.is_zero() {
+                return Err(Value::division_by_zero(&Value::Int(1)));
             }
-            (Value::Float(l), Value::Float(r)) => ((*l % r) + r) % r,
-            (Value::Int(l), Value::Float(r)) => ((*l as f64 % r) + r) % r,
-            (Value::Float(l), Value::Int(r)) => {
-                let r = r as f64;
-                ((*l % r) + r) % r
+            // 0, 1 and -1 stay that small for exponents of any size
+            if exp.is_zero() {
+                return Ok(Value::Int(1));
             }
-            _ => unreachable!(),
-        };
-
-        *self = Value::Float(result);
+            if base.is_zero() || base.is_one() {
+                return Ok(self);
+            }
+            if base == -BigRational::one() {
+                return Ok(Value::Int(if exp.is_even() { 1 } else { -1 }));
+            }
+            let too_large = || {
+                RuntimeError::new(
+                    RuntimeErrorKind::Overflow,
+                    format!("Result of {} ^ {} is too large", self, rhs),
+                )
+            };
+            let magnitude = exp.magnitude().to_u32().ok_or_else(too_large)?;
+            let bits = base.numer().bits().max(base.denom().bits());
+            if bits.saturating_sub(1) * magnitude as u64 > MAX_POW_BITS {
+                return Err(too_large());
+            }
+            let base = if exp.is_negative() {
+                base.recip()
+            } else {
+                base
+            };
+            return Ok(Value::from_rational(base.pow(magnitude)));
+        }
+        match self.float_operands(&rhs) {
+            Some((l, r)) => Ok(Value::float_result(l.powf(r))),
+            None => Err(Value::type_error("exponentiation", &self, &rhs)),
+        }
     }
-}
 
-impl std::ops::DivAssign for Value {
-    fn div_assign(&mut self, rhs: Value) {
-        let result: f64 = match (&*self, rhs) {
-            (Value::Int(l), Value::Int(r)) => *l as f64 / r as f64,
-            (Value::Float(l), Value::Float(r)) => *l / r,
-            (Value::Int(l), Value::Float(r)) => (*l as f64) / r,
-            (Value::Float(l), Value::Int(r)) => *l / (r as f64),
-            _ => unreachable!(),
-        };
-
-        if result.fract() == 0.0 {
-            *self = Value::Int(result as i64);
-        } else {
-            *self = Value::Float(result);
+    pub fn checked_negate(self) -> Result<Value, RuntimeError> {
+        match self {
+            Value::Float(v) => Ok(Value::Float(-v)),
+            Value::Int(v) => match v.checked_neg() {
+                Some(v) => Ok(Value::Int(v)),
+                None => Ok(Value::from_bigint(-BigInt::from(v))),
+            },
+            Value::BigInt(v) => Ok(Value::from_bigint(-v)),
+            Value::Rational(v) => Ok(Value::from_rational(-BigRational::clone(&v))),
+            other => Err(RuntimeError::type_mismatch(format!(
+                "Cannot negate {}",
+                other
+            ))),
         }
     }
-}
 
-impl Mul for Value {
-    type Output = Value;
+    /// Orders two numbers, `None` if they are unordered because one of them is NaN.
+    pub fn compare_to(&self, rhs: &Self) -> Result<Option<Ordering>, RuntimeError> {
+        if !(self.is_numeric() && rhs.is_numeric()) {
+            let msg = format!("Incompatible Types for comparision: {} and {}", self, rhs);
+            return Err(RuntimeError::type_mismatch(msg));
+        }
 
-    fn mul(self, rhs: Value) -> Value {
+        if let (Value::Int(l), Value::Int(r)) = (self, rhs) {
+            return Ok(Some(l.cmp(r)));
+        }
+        if let Some((l, r)) = self.integer_operands(rhs) {
+            return Ok(Some(l.cmp(&r)));
+        }
+        if let Some((l, r)) = self.rational_operands(rhs) {
+            return Ok(Some(l.cmp(&r)));
+        }
         match (self, rhs) {
-            (Value::Int(l), Value::Int(r)) => Value::Int(l * r),
-            (Value::Float(l), Value::Float(r)) => Value::Float(l * r),
-            (Value::Int(l), Value::Float(r)) => Value::Float(l as f64 * r),
-            (Value::Float(l), Value::Int(r)) => Value::Float(l * r as f64),
-            _ => Value::None,
+            (Value::Float(l), Value::Float(r)) => Ok(l.partial_cmp(r)),
+            // Integers up to 2^53 convert to floats exactly
+            (Value::Int(l), Value::Float(r)) if l.unsigned_abs() <= 1 << f64::MANTISSA_DIGITS => {
+                Ok((*l as f64).partial_cmp(r))
+            }
+            (Value::Float(l), _) => {
+                let ordering = compare_exact_to_float(&rhs.to_rational().unwrap(), *l);
+                Ok(ordering.map(Ordering::reverse))
+            }
+            (_, Value::Float(r)) => Ok(compare_exact_to_float(&self.to_rational().unwrap(), *r)),
+            _ => unreachable!(),
         }
     }
 }
 
+/// Orders an exact number against a float without rounding the exact number to a float, which
+/// would make e.g. 2^53 + 1 equal to 2^53 and 10^400 equal to inf.
+fn compare_exact_to_float(exact: &BigRational, float: f64) -> Option<Ordering> {
+    if float.is_nan() {
+        None
+    } else if float.is_infinite() {
+        Some(if float > 0.0 {
+            Ordering::Less
+        } else {
+            Ordering::Greater
+        })
+    } else {
+        // Every finite float is a rational, so this is exact
+        Some(exact.cmp(&BigRational::from_float(float)?))
+    }
+}
+
 impl PartialEq for Value {
     fn eq(&self, other: &Self) -> bool {
         match (self, other) {
             (Value::Int(l), Value::Int(r)) => l == r,
-            (Value::Float(l), Value::Float(r)) => (l - r).abs() < f64::EPSILON,
+            (Value::BigInt(l), Value::BigInt(r)) => l == r,
+            (Value::Rational(l), Value::Rational(r)) => l == r,
+            (Value::Float(l), Value::Float(r)) => l == r || (l - r).abs() < f64::EPSILON,
             (Value::Boolean(l), Value::Boolean(r)) => l == r,
             (Value::String(l), Value::String(r)) => l == r,
+            (Value::Symbol(l), Value::Symbol(r)) => l == r,
+            (Value::Nil, Value::Nil) => true,
+            (Value::Pair(_), Value::Pair(_)) => lists_equal(self, other),
+            (Value::Closure(l), Value::Closure(r)) => Rc::ptr_eq(l, r),
+            (Value::Builtin(l), Value::Builtin(r)) => std::ptr::eq(*l, *r),
+            (Value::Error(l), Value::Error(r)) => Rc::ptr_eq(l, r),
+            (Value::None, Value::None) => true,
             _ => false,
         }
     }
 }
 
+/// Compares two lists element by element, walking the tails in a loop instead of recursing.
+/// Nested lists in the elements still recurse, on a fresh stack segment when the stack runs low.
+fn lists_equal(mut l: &Value, mut r: &Value) -> bool {
+    loop {
+        match (l, r) {
+            (Value::Pair(lp), Value::Pair(rp)) => {
+                if Rc::ptr_eq(lp, rp) {
+                    return true;
+                }
+                if !stacker::maybe_grow(STACK_RED_ZONE, STACK_SEGMENT_SIZE, || lp.car == rp.car) {
+                    return false;
+                }
+                l = &lp.cdr;
+                r = &rp.cdr;
+            }
+            (l, r) => return l == r,
+        }
+    }
+}
+
 impl std::fmt::Display for Value {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Value::Int(v) => write!(f, "{}", v),
-            Value::Float(v) => write!(f, "{}", v),
+            Value::BigInt(v) => write!(f, "{}", v),
+            Value::Rational(v) => write!(f, "{}", v),
+            // Debug formatting keeps the decimal point of whole floats, e.g. 5.0
+            Value::Float(v) if v.is_nan() => write!(f, "nan"),
+            Value::Float(v) => write!(f, "{:?}", v),
             Value::Boolean(v) => write!(f, "{}", v),
-            Value::String(v) => write!(f, "\"{}\"", v),
+            // Escaped so that the printed string can be read back in
+            Value::String(v) => write!(f, "{:?}", v),
+            Value::Symbol(v) if !is_plain_symbol(v.name()) => {
+                write!(
+                    f,
+                    "|{}|",
+                    v.name().replace('\\', "\\\\").replace('|', "\\|")
+                )
+            }
+            Value::Symbol(v) => write!(f, "{}", v),
+            Value::Nil => write!(f, "()"),
+            // Elements that are lists themselves recurse, so deeply nested ones move to a fresh
+            // stack segment like the evaluator does
+            Value::Pair(pair) => stacker::maybe_grow(STACK_RED_ZONE, STACK_SEGMENT_SIZE, || {
+                write!(f, "({}", pair.car)?;
+                let mut rest = &pair.cdr;
+                while let Value::Pair(pair) = rest {
+                    write!(f, " {}", pair.car)?;
+                    rest = &pair.cdr;
+                }
+                // Improper lists end in a dot, e.g. (cons 1 2) is (1 . 2)
+                match rest {
+                    Value::Nil => write!(f, ")"),
+                    other => write!(f, " . {})", other),
+                }
+            }),
+            Value::Closure(c) => match &c.name {
+                Some(name) => write!(f, "<function {}>", name),
+                None => write!(f, "<lambda>"),
+            },
+            Value::Builtin(b) => write!(f, "<builtin {}>", b.name),
+            Value::Error(e) => write!(f, "<error {}: {}>", e.kind.name(), e.message),
             Value::None => write!(f, "null"),
-            other => write!(f, "{:?}", other),
         }
     }
 }
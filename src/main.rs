@@ -1,5 +1,11 @@
+pub mod cli;
 pub mod repl;
 
+// Deeply recursive scripts are fine, the evaluator grows its stack on the heap as needed
+const RECURSION_LIMIT: usize = 100_000;
+
 fn main() {
-    repl::run_repl();
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    lisp_interpreter::eval::set_recursion_limit(RECURSION_LIMIT);
+    std::process::exit(cli::main(&args));
 }
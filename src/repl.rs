@@ -1,19 +1,32 @@
-use lisp_interpreter::eval::eval;
+use lisp_interpreter::env::Environment;
+use lisp_interpreter::eval::{eval_with_options, EvalOptions};
+use lisp_interpreter::tokenize::is_complete;
 
+use crate::cli::print_error;
 use rustyline::error::ReadlineError;
 use rustyline::KeyPress;
 use rustyline::{Cmd, Editor};
 
-fn is_complete_expression(input: &str) -> bool {
-    let open = input.matches('(').count();
-    let close = input.matches(')').count();
-    open == close
+fn run_command(command: &str, options: &mut EvalOptions) {
+    match command.split_whitespace().collect::<Vec<_>>().as_slice() {
+        [":trace", "on"] => options.set_trace(true),
+        [":trace", "off"] => options.set_trace(false),
+        [":trace", "tokens"] => options.trace_tokens = !options.trace_tokens,
+        [":trace", "ast"] => options.trace_ast = !options.trace_ast,
+        [":trace", "eval"] => options.trace_eval = !options.trace_eval,
+        [":trace"] => println!("{:?}", options),
+        _ => eprintln!(
+            "Unknown command '{}', try :trace on|off|tokens|ast|eval",
+            command
+        ),
+    }
 }
 
-pub fn run_repl() {
+pub fn run_repl(mut options: EvalOptions, color: bool) {
     let mut rl = Editor::<()>::new();
     let _ = rl.load_history(".repl_history");
     let mut buffer = String::new();
+    let mut env = Environment::global();
 
     rl.bind_sequence(
         KeyPress::ControlLeft,
@@ -36,27 +49,26 @@ pub fn run_repl() {
                 if trimmed == "exit" || trimmed == "quit" {
                     break;
                 }
-                buffer.push_str(trimmed);
-                if buffer.is_empty() {
+                if buffer.is_empty() && trimmed.starts_with(':') {
+                    run_command(trimmed, &mut options);
+                    continue;
+                }
+                if buffer.is_empty() && trimmed.is_empty() {
                     continue;
                 }
+                // Lines are kept as typed, they may be part of a multi-line string
+                buffer.push_str(&line);
 
-                if is_complete_expression(&buffer) {
+                if is_complete(&buffer) {
                     rl.add_history_entry(&buffer);
-                    buffer = if !(buffer.starts_with('(') && buffer.ends_with(')')) {
-                        format!("({})", buffer)
-                    } else {
-                        buffer
-                    };
-
-                    match eval(&buffer) {
+                    match eval_with_options(&buffer, &mut env, &options) {
                         Ok(result) => println!("{}", result),
-                        Err(err) => eprintln!("Error: {}", err.message()),
+                        Err(err) => print_error(&err, &buffer, None, color),
                     }
 
                     buffer.clear();
                 } else {
-                    buffer.push(' ');
+                    buffer.push('\n');
                 }
             }
             Err(ReadlineError::Interrupted) => {